@@ -0,0 +1,91 @@
+use criterion::{black_box, criterion_group, BenchmarkId, Criterion, Throughput};
+
+#[allow(dead_code)]
+#[path = "../src/codec.rs"]
+mod codec;
+
+#[allow(dead_code)]
+#[path = "../src/record.rs"]
+mod record;
+
+use codec::Encoding;
+use record::{FileData, FileKind};
+
+const RECORDS: usize = 10_000;
+
+/// Records shaped like those of a source tree.
+fn records() -> Vec<FileData> {
+    (0..RECORDS)
+        .map(|i| FileData {
+            path: format!("/home/dev/src/project-{}/crates/module-{}/src/file_{}.rs", i % 7, i % 113, i),
+            kind: if i % 10 == 0 { FileKind::Dir } else { FileKind::File },
+            size: (i as u64 * 7919) % 1_000_000,
+            mtime: 1_600_000_000 + i as i64,
+            ctime: 1_600_000_000 + i as i64,
+            mode: 0o100644,
+            uid: 1000,
+            gid: 1000,
+            dev: 2049,
+            ino: 1_000_000 + i as u64,
+        })
+        .collect()
+}
+
+const ENCODINGS: [Encoding; 2] = [Encoding::Json, Encoding::Bincode];
+
+/// Prints the bytes each encoding stores for the sample records.
+fn sizes(records: &[FileData]) {
+    let json: usize = records.iter().map(|r| Encoding::Json.encode(r).unwrap().len()).sum();
+    for encoding in &ENCODINGS {
+        let bytes: usize = records.iter().map(|r| encoding.encode(r).unwrap().len()).sum();
+        println!(
+            "{:>8}: {} bytes for {} records, {:.1} per record, {:.0}% of json",
+            encoding.name(),
+            bytes,
+            records.len(),
+            bytes as f64 / records.len() as f64,
+            100.0 * bytes as f64 / json as f64
+        );
+    }
+}
+
+fn encode(c: &mut Criterion) {
+    let records = records();
+    let mut group = c.benchmark_group("encode");
+    group.throughput(Throughput::Elements(records.len() as u64));
+    for encoding in &ENCODINGS {
+        group.bench_with_input(BenchmarkId::from_parameter(encoding.name()), &records, |b, records| {
+            b.iter(|| {
+                for r in records {
+                    black_box(encoding.encode(r).unwrap());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let records = records();
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Elements(records.len() as u64));
+    for encoding in &ENCODINGS {
+        let stored: Vec<Vec<u8>> = records.iter().map(|r| encoding.encode(r).unwrap()).collect();
+        group.bench_with_input(BenchmarkId::from_parameter(encoding.name()), &stored, |b, stored| {
+            b.iter(|| {
+                for bytes in stored {
+                    black_box(codec::decode::<FileData>(bytes).unwrap());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encode, decode);
+
+fn main() {
+    sizes(&records());
+    benches();
+    criterion::Criterion::default().configure_from_args().final_summary();
+}
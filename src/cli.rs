@@ -0,0 +1,881 @@
+use crate::config::Config;
+use crate::daemon::{Daemon, Options};
+use crate::error::{Error, EXIT_NOT_FOUND, EXIT_OK};
+use crate::exclude::Excludes;
+use crate::fdb::{Fdb, File};
+#[cfg(unix)]
+use crate::ipc::{Call, Client, Error as IpcError, Response};
+use crate::output::{self, Column, Format};
+use crate::search::{Mode, Order, Query, Sort, Target};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::env;
+use std::fs;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("quind")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Keeps an index of file names and searches it")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("db")
+                .long("db")
+                .value_name("PATH")
+                .env("QUIND_DB")
+                .help("Database directory"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("PATH")
+                .env("QUIND_CONFIG")
+                .help("Configuration file to read instead of the system and user ones"),
+        )
+        .arg(
+            Arg::with_name("socket")
+                .long("socket")
+                .value_name("PATH")
+                .env("QUIND_SOCKET")
+                .help("Socket where a running daemon answers queries"),
+        )
+        .arg(
+            Arg::with_name("ignore-file")
+                .long("ignore-file")
+                .value_name("PATH")
+                .env("QUIND_IGNORE")
+                .help("Global ignore file in gitignore syntax"),
+        )
+        .subcommand(
+            SubCommand::with_name("index")
+                .about("Adds every entry under a root, or under each configured root, to the database")
+                .arg(Arg::with_name("root"))
+                .arg(
+                    Arg::with_name("collection")
+                        .short("c")
+                        .long("collection")
+                        .value_name("NAME")
+                        .help("Keeps the entries in the collection NAME [default: the configured one, or the root's path]"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("search")
+                .about("Prints the paths of entries whose name contains the pattern")
+                .arg(Arg::with_name("pattern").required_unless("where"))
+                .arg(
+                    Arg::with_name("where")
+                        .short("w")
+                        .long("where")
+                        .value_name("EXPR")
+                        .help("Keeps entries matching an expression such as 'ext:log size:>100M mtime:<2d type:f'"),
+                )
+                .arg(
+                    Arg::with_name("ignore-case")
+                        .short("i")
+                        .long("ignore-case")
+                        .help("Ignores case when matching"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .short("p")
+                        .long("path")
+                        .help("Matches against the full path instead of the name"),
+                )
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .help("Matches names or paths starting with the pattern"),
+                )
+                .arg(
+                    Arg::with_name("glob")
+                        .short("g")
+                        .long("glob")
+                        .conflicts_with("prefix")
+                        .help("Treats the pattern as a shell glob such as '*.rs' or 'src/**/mod.rs'"),
+                )
+                .arg(
+                    Arg::with_name("regex")
+                        .short("r")
+                        .long("regex")
+                        .conflicts_with_all(&["prefix", "glob"])
+                        .help("Treats the pattern as a regular expression"),
+                )
+                .arg(
+                    Arg::with_name("fuzzy")
+                        .short("f")
+                        .long("fuzzy")
+                        .conflicts_with_all(&["prefix", "glob", "regex"])
+                        .help("Ranks paths containing the pattern's characters in order, best first"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .short("n")
+                        .long("limit")
+                        .value_name("N")
+                        .help("Prints at most N results, then the cursor for the next page on stderr"),
+                )
+                .arg(
+                    Arg::with_name("sort")
+                        .long("sort")
+                        .value_name("KEY")
+                        .possible_values(&["name", "path", "size", "mtime", "relevance"])
+                        .help("Orders results by KEY [default: relevance with --fuzzy, path otherwise]"),
+                )
+                .arg(
+                    Arg::with_name("asc")
+                        .long("asc")
+                        .help("Orders results smallest or oldest first; the default except for relevance"),
+                )
+                .arg(
+                    Arg::with_name("desc")
+                        .long("desc")
+                        .conflicts_with("asc")
+                        .help("Orders results largest, newest or best first"),
+                )
+                .arg(
+                    Arg::with_name("offset")
+                        .long("offset")
+                        .value_name("N")
+                        .help("Skips the first N results"),
+                )
+                .arg(
+                    Arg::with_name("in")
+                        .long("in")
+                        .value_name("COLLECTION")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Searches only the named collection; may be repeated"),
+                )
+                .arg(
+                    Arg::with_name("after")
+                        .long("after")
+                        .value_name("CURSOR")
+                        .help("Resumes after the result a previous page's cursor was made for"),
+                )
+                .arg(
+                    Arg::with_name("null")
+                        .short("0")
+                        .long("null")
+                        .help("Ends each path with a NUL byte instead of a newline"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .conflicts_with("null")
+                        .help("Prints each result as a JSON object on its own line"),
+                )
+                .arg(
+                    Arg::with_name("columns")
+                        .long("columns")
+                        .value_name("LIST")
+                        .conflicts_with_all(&["null", "json"])
+                        .help("Prints the comma-separated fields, such as 'path,size,mtime', separated by tabs"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Follows changes under a root, or under the configured roots, until interrupted; a running daemon takes them on instead")
+                .arg(Arg::with_name("root"))
+                .arg(
+                    Arg::with_name("debounce")
+                        .long("debounce")
+                        .value_name("MS")
+                        .help("Merges changes to a path made within MS milliseconds; 0 disables [default: 200]"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("daemon")
+                .about("Follows changes under the configured roots until stopped; SIGHUP reloads the configuration")
+                .arg(
+                    Arg::with_name("detach")
+                        .short("d")
+                        .long("detach")
+                        .help("Runs in the background"),
+                )
+                .arg(
+                    Arg::with_name("log-file")
+                        .long("log-file")
+                        .value_name("PATH")
+                        .requires("detach")
+                        .help("Appends log messages to PATH when running in the background"),
+                )
+                .arg(
+                    Arg::with_name("debounce")
+                        .long("debounce")
+                        .value_name("MS")
+                        .help("Merges changes to a path made within MS milliseconds; 0 disables [default: 200]"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("status").about("Shows the database location and size"))
+        .subcommand(
+            SubCommand::with_name("reindex")
+                .about("Rebuilds the name index used to speed up searches"),
+        )
+        .subcommand(
+            SubCommand::with_name("forget")
+                .about("Drops every entry under a root from the database")
+                .arg(Arg::with_name("root").required(true)),
+        )
+        .subcommand(SubCommand::with_name("collections").about("Lists the collections with their roots and sizes"))
+        .subcommand(
+            SubCommand::with_name("drop")
+                .about("Drops a collection with every entry in it")
+                .arg(Arg::with_name("name").required(true)),
+        )
+}
+
+/// Runs the subcommand selected in `m` and returns the exit status.
+pub fn run(m: &ArgMatches) -> Result<i32, Error> {
+    let config = Config::load(&config_paths(m)?)?;
+    log::set_max_level(config.log_level);
+    let db = db_path(m, &config);
+    let socket = socket_path(m, &config, &db);
+    if let Some(code) = remote(m, &config, &socket)? {
+        return Ok(code);
+    }
+    let excludes = Arc::new(config.excludes(Some(&ignore_path(m)))?);
+    if let ("daemon", Some(sub)) = m.subcommand() {
+        if sub.is_present("detach") {
+            detach(sub.value_of_os("log-file").map(Path::new))?;
+        }
+    }
+    // Loading creates the database, which a status check should not do.
+    if let ("status", Some(_)) = m.subcommand() {
+        if !db.exists() {
+            println!("database: {}", db.display());
+            println!("entries: 0 (not created yet)");
+            return Ok(EXIT_OK);
+        }
+    }
+    let mut fdb = Fdb::load(db, String::from("quind"))?;
+    fdb.set_excludes(Arc::clone(&excludes));
+    match m.subcommand() {
+        ("index", Some(sub)) => index(&fdb, &roots(sub, &config, false)?, |root| collection(sub, &config, root)),
+        ("search", Some(sub)) => search(&fdb, &query(sub)?, &format(sub)?),
+        ("watch", Some(sub)) => watch(m, sub, fdb, config, excludes, socket),
+        ("daemon", Some(sub)) => daemon(m, sub, fdb, config, excludes, socket),
+        ("status", Some(_)) => status(&fdb),
+        ("reindex", Some(_)) => reindex(&fdb),
+        ("forget", Some(sub)) => forget(&fdb, &old_root(sub)?),
+        ("collections", Some(_)) => collections(&fdb),
+        ("drop", Some(sub)) => drop_collection(&fdb, sub.value_of("name").unwrap()),
+        (cmd, _) => Err(Error::Usage(format!("unknown subcommand '{}'", cmd))),
+    }
+}
+
+fn config_paths(m: &ArgMatches) -> Result<Vec<PathBuf>, Error> {
+    match m.value_of_os("config") {
+        Some(p) if Path::new(p).is_file() => Ok(vec![PathBuf::from(p)]),
+        Some(p) => Err(Error::Usage(format!("{}: no such file", Path::new(p).display()))),
+        None => Ok(Config::default_paths()),
+    }
+}
+
+fn db_path(m: &ArgMatches, config: &Config) -> PathBuf {
+    if let Some(p) = m.value_of_os("db") {
+        return PathBuf::from(p);
+    }
+    if let Some(p) = &config.db {
+        return p.clone();
+    }
+    match (env::var_os("XDG_DATA_HOME"), env::var_os("HOME")) {
+        (Some(data), _) => PathBuf::from(data).join("quind"),
+        (None, Some(home)) => PathBuf::from(home).join(".local/share/quind"),
+        (None, None) => PathBuf::from(".quind"),
+    }
+}
+
+fn socket_path(m: &ArgMatches, config: &Config, db: &Path) -> PathBuf {
+    if let Some(p) = m.value_of_os("socket") {
+        return PathBuf::from(p);
+    }
+    match &config.socket {
+        Some(p) => p.clone(),
+        None => db.join("quind.sock"),
+    }
+}
+
+fn ignore_path(m: &ArgMatches) -> PathBuf {
+    if let Some(p) = m.value_of_os("ignore-file") {
+        return PathBuf::from(p);
+    }
+    match (env::var_os("XDG_CONFIG_HOME"), env::var_os("HOME")) {
+        (Some(config), _) => PathBuf::from(config).join("quind/ignore"),
+        (None, Some(home)) => PathBuf::from(home).join(".config/quind/ignore"),
+        (None, None) => PathBuf::from(".quindignore"),
+    }
+}
+
+fn root(m: &ArgMatches) -> Result<PathBuf, Error> {
+    let root = Path::new(m.value_of_os("root").unwrap());
+    match fs::canonicalize(root) {
+        Ok(p) => Ok(p),
+        Err(e) => Err(Error::Usage(format!("{}: {}", root.display(), e))),
+    }
+}
+
+/// The root given on the command line like `root`, except that one which is gone is
+/// taken as given, made absolute, so that its entries can still be dropped.
+fn old_root(m: &ArgMatches) -> Result<PathBuf, Error> {
+    let root = Path::new(m.value_of_os("root").unwrap());
+    match fs::canonicalize(root) {
+        Ok(p) => Ok(p),
+        Err(_) => Ok(env::current_dir()?.join(root)),
+    }
+}
+
+/// The root given on the command line, or else the configured ones, only those to be
+/// watched if `watching`. Configured roots that cannot be found are left out.
+fn roots(m: &ArgMatches, config: &Config, watching: bool) -> Result<Vec<PathBuf>, Error> {
+    if m.is_present("root") {
+        return Ok(vec![root(m)?]);
+    }
+    let roots = config.live_roots(watching);
+    match roots.is_empty() {
+        true => Err(Error::Usage(String::from("no root given and none configured"))),
+        false => Ok(roots),
+    }
+}
+
+/// The collection `index` keeps `root` in: the one given on the command line, or else
+/// the configured one.
+fn collection(m: &ArgMatches, config: &Config, root: &Path) -> String {
+    match m.value_of("collection") {
+        Some(name) => String::from(name),
+        None => config.collection(root),
+    }
+}
+
+fn index<F>(fdb: &Fdb, roots: &[PathBuf], collection: F) -> Result<i32, Error>
+where
+    F: Fn(&Path) -> String,
+{
+    for root in roots {
+        fdb.register(root, &collection(root))?;
+        let stats = fdb.index_tree(root)?;
+        println!(
+            "{}: {} added, {} skipped, {} failed",
+            root.display(),
+            stats.added,
+            stats.skipped,
+            stats.failed
+        );
+    }
+    Ok(EXIT_OK)
+}
+
+fn query(m: &ArgMatches) -> Result<Query, Error> {
+    let mode = if m.is_present("prefix") {
+        Mode::Prefix
+    } else if m.is_present("glob") {
+        Mode::Glob
+    } else if m.is_present("regex") {
+        Mode::Regex
+    } else if m.is_present("fuzzy") {
+        Mode::Fuzzy
+    } else {
+        Mode::Substring
+    };
+    let target = match m.is_present("path") {
+        true => Target::Path,
+        false => Target::Name,
+    };
+    let mut q = Query::new(m.value_of("pattern").unwrap_or(""))
+        .mode(mode)
+        .target(target)
+        .ignore_case(m.is_present("ignore-case"));
+    if m.is_present("limit") {
+        q = q.limit(number(m, "limit")? as usize);
+    }
+    if let Some(expr) = m.value_of("where") {
+        q = q.filter(expr);
+    }
+    q.sort = match m.value_of("sort") {
+        Some("name") => Some(Sort::Name),
+        Some("path") => Some(Sort::Path),
+        Some("size") => Some(Sort::Size),
+        Some("mtime") => Some(Sort::Mtime),
+        Some("relevance") => Some(Sort::Relevance),
+        _ => None,
+    };
+    if m.is_present("asc") {
+        q = q.order(Order::Asc);
+    } else if m.is_present("desc") {
+        q = q.order(Order::Desc);
+    }
+    if m.is_present("offset") {
+        q = q.offset(number(m, "offset")? as usize);
+    }
+    if let Some(cursor) = m.value_of("after") {
+        q = q.after(cursor);
+    }
+    for name in m.values_of("in").into_iter().flatten() {
+        q = q.collection(name);
+    }
+    Ok(q)
+}
+
+fn number(m: &ArgMatches, arg: &str) -> Result<u64, Error> {
+    let value = m.value_of(arg).unwrap();
+    match value.parse() {
+        Ok(n) => Ok(n),
+        Err(_) => Err(Error::Usage(format!("invalid {} '{}'", arg, value))),
+    }
+}
+
+fn format(m: &ArgMatches) -> Result<Format, Error> {
+    if let Some(list) = m.value_of("columns") {
+        return Column::parse_list(list).map(Format::Columns).map_err(Error::Usage);
+    }
+    Ok(if m.is_present("json") {
+        Format::Json
+    } else if m.is_present("null") {
+        Format::Null
+    } else {
+        Format::Plain
+    })
+}
+
+/// Prints the results of `q`. Unlimited searches stream them; limited ones are a page
+/// followed by the cursor for the next, if any.
+fn search(fdb: &Fdb, q: &Query, format: &Format) -> Result<i32, Error> {
+    if q.limit.is_some() {
+        let page = fdb.page(q)?;
+        let code = print_files(page.files, format)?;
+        print_next(page.next);
+        return Ok(code);
+    }
+    let mut failed = None;
+    let files = fdb.search(q)?.map_while(|r| r.map_err(|e| failed = Some(e)).ok());
+    let code = print_files(files, format)?;
+    match failed {
+        Some(e) => Err(Error::DB(e)),
+        None => Ok(code),
+    }
+}
+
+/// Reports the cursor for the next page on stderr, leaving stdout to the results.
+fn print_next(next: Option<String>) {
+    if let Some(cursor) = next {
+        eprintln!("more results: --after {}", cursor);
+    }
+}
+
+fn print_files<I: IntoIterator<Item = File>>(files: I, format: &Format) -> Result<i32, Error> {
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    match output::write(&mut out, format, files) {
+        Ok(0) => Ok(EXIT_NOT_FOUND),
+        Ok(_) => Ok(EXIT_OK),
+        // The reader, such as `head`, has seen enough.
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(EXIT_OK),
+        Err(e) => Err(Error::IO(e)),
+    }
+}
+
+/// Prints the results of `q` from the daemon, which answers in pages, asking for the
+/// next page until there are no more or `q.limit` results were printed.
+#[cfg(unix)]
+fn remote_search(client: &mut Client, mut q: Query, format: &Format) -> Result<i32, Error> {
+    let mut wanted = q.limit;
+    let mut code = EXIT_NOT_FOUND;
+    loop {
+        let (files, next) = match client.call(Call::Search { query: q.clone() })? {
+            Response::Files { files, next } => (files, next),
+            _ => return Err(Error::Ipc(IpcError::Unexpected)),
+        };
+        wanted = wanted.map(|n| n.saturating_sub(files.len()));
+        if print_files(files, format)? == EXIT_OK {
+            code = EXIT_OK;
+        }
+        match next {
+            Some(cursor) if wanted != Some(0) => {
+                q.limit = wanted;
+                q = q.after(cursor).offset(0);
+            }
+            next => {
+                print_next(next);
+                return Ok(code);
+            }
+        }
+    }
+}
+
+/// Runs the subcommand through the daemon answering on `socket`, if one is, and
+/// returns its exit status. Subcommands that need the store to themselves fail then.
+#[cfg(unix)]
+fn remote(m: &ArgMatches, config: &Config, socket: &Path) -> Result<Option<i32>, Error> {
+    let mut client = match Client::connect(socket) {
+        Some(client) => client,
+        None => return Ok(None),
+    };
+    let code = match m.subcommand() {
+        ("search", Some(sub)) => remote_search(&mut client, query(sub)?, &format(sub)?)?,
+        ("status", Some(_)) => match client.call(Call::Status)? {
+            Response::Status { db, entries, roots } => {
+                println!("database: {}", db.display());
+                println!("entries: {}", entries);
+                println!("daemon: {}", socket.display());
+                for root in roots {
+                    println!("watching: {}", root.display());
+                }
+                EXIT_OK
+            }
+            _ => return Err(Error::Ipc(IpcError::Unexpected)),
+        },
+        ("index", Some(sub)) => {
+            for path in roots(sub, config, false)? {
+                let collection = sub.value_of("collection").map(String::from);
+                match client.call(Call::Index { path, collection })? {
+                    Response::Indexed { root, stats } => println!(
+                        "{}: {} added, {} skipped, {} failed",
+                        root.display(),
+                        stats.added,
+                        stats.skipped,
+                        stats.failed
+                    ),
+                    _ => return Err(Error::Ipc(IpcError::Unexpected)),
+                }
+            }
+            EXIT_OK
+        }
+        ("watch", Some(sub)) => {
+            for path in roots(sub, config, true)? {
+                match client.call(Call::AddRoot { path, collection: None })? {
+                    Response::Added { root, stats } => println!(
+                        "{}: watched by the daemon, {} added, {} removed, {} failed",
+                        root.display(),
+                        stats.added,
+                        stats.removed,
+                        stats.failed
+                    ),
+                    _ => return Err(Error::Ipc(IpcError::Unexpected)),
+                }
+            }
+            EXIT_OK
+        }
+        ("forget", Some(sub)) => match client.call(Call::RemoveRoot { path: old_root(sub)? })? {
+            Response::Removed { root, entries } => {
+                println!("{}: {} entries removed", root.display(), entries);
+                EXIT_OK
+            }
+            _ => return Err(Error::Ipc(IpcError::Unexpected)),
+        },
+        (cmd, _) => {
+            let message = format!("'{}' needs the database, which the daemon on {} holds", cmd, socket.display());
+            return Err(Error::Usage(message));
+        }
+    };
+    Ok(Some(code))
+}
+
+#[cfg(not(unix))]
+fn remote(_m: &ArgMatches, _config: &Config, _socket: &Path) -> Result<Option<i32>, Error> {
+    Ok(None)
+}
+
+fn watch(
+    m: &ArgMatches,
+    sub: &ArgMatches,
+    fdb: Fdb,
+    config: Config,
+    excludes: Arc<Excludes>,
+    socket: PathBuf,
+) -> Result<i32, Error> {
+    let roots = roots(sub, &config, true)?;
+    let options = Options {
+        roots: match sub.is_present("root") {
+            true => Some(roots),
+            false => None,
+        },
+        debounce: debounce(sub)?,
+        ignore: Some(ignore_path(m)),
+        socket: if cfg!(unix) { Some(socket) } else { None },
+    };
+    serve(Daemon::new(fdb, config, excludes, options))
+}
+
+fn daemon(
+    m: &ArgMatches,
+    sub: &ArgMatches,
+    fdb: Fdb,
+    config: Config,
+    excludes: Arc<Excludes>,
+    socket: PathBuf,
+) -> Result<i32, Error> {
+    let options = Options {
+        roots: None,
+        debounce: debounce(sub)?,
+        ignore: Some(ignore_path(m)),
+        socket: if cfg!(unix) { Some(socket) } else { None },
+    };
+    serve(Daemon::new(fdb, config, excludes, options))
+}
+
+fn debounce(m: &ArgMatches) -> Result<Option<Duration>, Error> {
+    match m.is_present("debounce") {
+        true => Ok(Some(Duration::from_millis(number(m, "debounce")?))),
+        false => Ok(None),
+    }
+}
+
+fn serve(daemon: Daemon) -> Result<i32, Error> {
+    daemon.handle_signals()?;
+    daemon.run()?;
+    Ok(EXIT_OK)
+}
+
+/// Carries on in a new session in the background, with the standard streams on
+/// `/dev/null` except for stderr when `log` is given. Must run before any thread starts.
+#[cfg(unix)]
+fn detach(log: Option<&Path>) -> Result<(), Error> {
+    use std::fs::OpenOptions;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    let err = match log {
+        Some(p) => OpenOptions::new().create(true).append(true).open(p)?,
+        None => null.try_clone()?,
+    };
+    // SAFETY: no other thread is running yet, so the child is a full copy of this
+    // process; the parent leaves at once without running destructors.
+    unsafe {
+        match libc::fork() {
+            -1 => return Err(Error::IO(io::Error::last_os_error())),
+            0 => {}
+            _ => libc::_exit(EXIT_OK),
+        }
+        if libc::setsid() == -1 {
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        for (from, to) in &[(null.as_raw_fd(), 0), (null.as_raw_fd(), 1), (err.as_raw_fd(), 2)] {
+            if libc::dup2(*from, *to) == -1 {
+                return Err(Error::IO(io::Error::last_os_error()));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn detach(_log: Option<&Path>) -> Result<(), Error> {
+    Err(Error::Usage(String::from("--detach is only supported on Unix")))
+}
+
+fn status(fdb: &Fdb) -> Result<i32, Error> {
+    println!("database: {}", fdb.path.display());
+    println!("entries: {}", fdb.count()?);
+    println!("schema: {}", fdb.version()?);
+    println!("encoding: {}", fdb.encoding()?.name());
+    Ok(EXIT_OK)
+}
+
+fn reindex(fdb: &Fdb) -> Result<i32, Error> {
+    let indexed = fdb.reindex()?;
+    println!("{} entries reindexed", indexed);
+    Ok(EXIT_OK)
+}
+
+fn forget(fdb: &Fdb, root: &Path) -> Result<i32, Error> {
+    let removed = fdb.forget(root)?;
+    println!("{}: {} entries removed", root.display(), removed);
+    Ok(EXIT_OK)
+}
+
+fn collections(fdb: &Fdb) -> Result<i32, Error> {
+    for c in fdb.collections()? {
+        println!("{}: {} entries", c.name, c.entries);
+        for root in c.roots {
+            println!("  root: {}", root.display());
+        }
+    }
+    Ok(EXIT_OK)
+}
+
+fn drop_collection(fdb: &Fdb, name: &str) -> Result<i32, Error> {
+    let removed = fdb.drop_collection(name)?;
+    println!("{}: dropped, {} entries removed", name, removed);
+    Ok(EXIT_OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::{Error as ConfigError, Root};
+    use crate::error::{EXIT_DB, EXIT_IO, EXIT_MONITOR, EXIT_USAGE};
+    use crate::fdb::Error as FdbError;
+    use crate::monitor::Error as MonitorError;
+    use std::ffi::OsStr;
+    use std::io;
+    use std::sync::mpsc;
+
+    #[test]
+    fn subcommand_is_required() {
+        assert!(app().get_matches_from_safe(vec!["quind"]).is_err());
+        assert!(app().get_matches_from_safe(vec!["quind", "search"]).is_err());
+    }
+
+    #[test]
+    fn db_option_overrides_default() {
+        let m = app()
+            .get_matches_from_safe(vec!["quind", "--db", "/tmp/q", "status"])
+            .unwrap();
+        assert_eq!(db_path(&m, &Config::default()), PathBuf::from("/tmp/q"));
+        let m = app()
+            .get_matches_from_safe(vec!["quind", "--ignore-file", "/tmp/i", "status"])
+            .unwrap();
+        assert_eq!(ignore_path(&m), PathBuf::from("/tmp/i"));
+
+        let mut config = Config::default();
+        config.db = Some(PathBuf::from("/srv/quind"));
+        assert_eq!(db_path(&m, &config), PathBuf::from("/srv/quind"));
+        let m = app()
+            .get_matches_from_safe(vec!["quind", "--config", "/nonexistent/quind.toml", "status"])
+            .unwrap();
+        assert!(config_paths(&m).is_err());
+    }
+
+    #[test]
+    fn roots_come_from_the_config_unless_given() {
+        let mut config = Config::default();
+        let m = app().get_matches_from_safe(vec!["quind", "index"]).unwrap();
+        let sub = m.subcommand_matches("index").unwrap();
+        assert!(roots(sub, &config, false).is_err());
+
+        let here = fs::canonicalize(".").unwrap();
+        config.roots = vec![
+            Root { path: here.clone(), exclude: Vec::new(), watch: false, collection: None },
+            Root { path: PathBuf::from("/nonexistent"), exclude: Vec::new(), watch: true, collection: None },
+        ];
+        assert_eq!(roots(sub, &config, false).unwrap(), vec![here.clone()]);
+        let m = app().get_matches_from_safe(vec!["quind", "watch"]).unwrap();
+        assert!(roots(m.subcommand_matches("watch").unwrap(), &config, true).is_err());
+        let m = app().get_matches_from_safe(vec!["quind", "watch", "."]).unwrap();
+        assert_eq!(roots(m.subcommand_matches("watch").unwrap(), &config, true).unwrap(), vec![here]);
+    }
+
+    #[test]
+    fn search_flags_build_query() {
+        let m = app()
+            .get_matches_from_safe(vec!["quind", "search", "-gip", "src/**/*.RS"])
+            .unwrap();
+        let q = query(m.subcommand_matches("search").unwrap()).unwrap();
+        assert_eq!(q.mode, Mode::Glob);
+        assert_eq!(q.target, Target::Path);
+        assert!(q.ignore_case);
+        assert_eq!(q.limit, None);
+        assert!(app()
+            .get_matches_from_safe(vec!["quind", "search", "--glob", "--prefix", "x"])
+            .is_err());
+
+        let m = app()
+            .get_matches_from_safe(vec!["quind", "search", "-f", "-n", "5", "qnd"])
+            .unwrap();
+        let q = query(m.subcommand_matches("search").unwrap()).unwrap();
+        assert_eq!(q.mode, Mode::Fuzzy);
+        assert_eq!(q.limit, Some(5));
+        let m = app()
+            .get_matches_from_safe(vec!["quind", "search", "-n", "five", "qnd"])
+            .unwrap();
+        assert!(query(m.subcommand_matches("search").unwrap()).is_err());
+
+        let m = app()
+            .get_matches_from_safe(vec!["quind", "search", "-w", "ext:rs size:>1k"])
+            .unwrap();
+        let q = query(m.subcommand_matches("search").unwrap()).unwrap();
+        assert_eq!(q.pattern, "");
+        assert_eq!(q.filter.as_deref(), Some("ext:rs size:>1k"));
+
+        let m = app()
+            .get_matches_from_safe(vec!["quind", "search", "--sort", "size", "--desc", "--offset", "20", "--after", "7b7d", "x"])
+            .unwrap();
+        let q = query(m.subcommand_matches("search").unwrap()).unwrap();
+        assert_eq!(q.ordering(), (Sort::Size, Order::Desc));
+        assert_eq!(q.offset, 20);
+        assert_eq!(q.after.as_deref(), Some("7b7d"));
+        let m = app()
+            .get_matches_from_safe(vec!["quind", "search", "--in", "src", "--in", "docs", "x"])
+            .unwrap();
+        assert_eq!(query(m.subcommand_matches("search").unwrap()).unwrap().collections, vec!["src", "docs"]);
+        assert!(app()
+            .get_matches_from_safe(vec!["quind", "search", "--sort", "owner", "x"])
+            .is_err());
+        assert!(app()
+            .get_matches_from_safe(vec!["quind", "search", "--asc", "--desc", "x"])
+            .is_err());
+    }
+
+    #[test]
+    fn output_flags_pick_a_format() {
+        let pick = |args: Vec<&str>| {
+            let m = app().get_matches_from_safe(args).unwrap();
+            format(m.subcommand_matches("search").unwrap())
+        };
+        assert_eq!(pick(vec!["quind", "search", "x"]).unwrap(), Format::Plain);
+        assert_eq!(pick(vec!["quind", "search", "-0", "x"]).unwrap(), Format::Null);
+        assert_eq!(pick(vec!["quind", "search", "--json", "x"]).unwrap(), Format::Json);
+        assert_eq!(
+            pick(vec!["quind", "search", "--columns", "path,size", "x"]).unwrap(),
+            Format::Columns(vec![Column::Path, Column::Size])
+        );
+        assert!(pick(vec!["quind", "search", "--columns", "owner", "x"]).is_err());
+        assert!(app()
+            .get_matches_from_safe(vec!["quind", "search", "--json", "-0", "x"])
+            .is_err());
+    }
+
+    #[test]
+    fn watch_takes_a_debounce_window() {
+        let m = app()
+            .get_matches_from_safe(vec!["quind", "watch", "--debounce", "50", "/"])
+            .unwrap();
+        assert_eq!(number(m.subcommand_matches("watch").unwrap(), "debounce").unwrap(), 50);
+        let m = app()
+            .get_matches_from_safe(vec!["quind", "watch", "--debounce", "soon", "/"])
+            .unwrap();
+        assert!(number(m.subcommand_matches("watch").unwrap(), "debounce").is_err());
+    }
+
+    #[test]
+    fn exit_codes_are_distinct() {
+        let errors = vec![
+            Error::IO(io::Error::from(io::ErrorKind::NotFound)),
+            Error::DB(FdbError::KVInitError),
+            Error::Monitor(MonitorError::Sync(mpsc::RecvError)),
+            Error::Usage(String::from("bad")),
+            Error::Config(ConfigError::Invalid {
+                path: PathBuf::from("config.toml"),
+                field: String::from("log_level"),
+                message: String::from("bad"),
+            }),
+        ];
+        let codes: Vec<i32> = errors.iter().map(|e| e.exit_code()).collect();
+        assert_eq!(codes, vec![EXIT_IO, EXIT_DB, EXIT_MONITOR, EXIT_USAGE, EXIT_USAGE]);
+        for e in errors {
+            assert!(!e.to_string().is_empty());
+        }
+    }
+    #[test]
+    fn deleted_roots_can_be_forgotten() {
+        let dir = Path::new("./instance/cli_forget");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir.join("tree")).unwrap();
+        fs::write(dir.join("tree/gone.txt"), "").unwrap();
+        let tree = fs::canonicalize(dir.join("tree")).unwrap();
+        let fdb = Fdb::new(dir.join("db"), String::from("test")).unwrap();
+        fdb.index_tree(&tree).unwrap();
+        fs::remove_dir_all(&tree).unwrap();
+
+        let m = app().get_matches_from_safe(vec![OsStr::new("quind"), OsStr::new("forget"), tree.as_os_str()]).unwrap();
+        let sub = m.subcommand_matches("forget").unwrap();
+        assert!(root(sub).is_err());
+        assert_eq!(old_root(sub).unwrap(), tree);
+        assert_eq!(forget(&fdb, &tree).unwrap(), EXIT_OK);
+        assert!(!fdb.check("gone.txt").unwrap());
+    }
+}
@@ -0,0 +1,192 @@
+use kv::{Raw, Value};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use thiserror::Error as TError;
+
+#[derive(Debug, TError)]
+pub enum Error {
+    #[error("Json error: Convert failed: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[cfg(feature = "binary")]
+    #[error("Bincode error: Convert failed: {0}")]
+    Bincode(#[from] bincode::Error),
+
+    #[error("records encoded as {0} cannot be read by this build; build quind with the `binary` feature")]
+    Unsupported(&'static str),
+
+    #[error("unknown record encoding (first byte {0:#04x})")]
+    Unknown(u8),
+
+    #[error("expected a record, found an index entry")]
+    Marker,
+}
+
+/// How records are laid out in the store.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Json,
+    /// bincode behind a leading tag byte; JSON records always open with `{`, so the
+    /// two can be told apart record by record.
+    Bincode,
+}
+
+/// The byte every bincode record opens with.
+const BINCODE_TAG: u8 = 1;
+
+impl Encoding {
+    /// The encoding this build writes: bincode with the `binary` feature, JSON otherwise.
+    #[cfg(feature = "binary")]
+    pub const CURRENT: Encoding = Encoding::Bincode;
+    #[cfg(not(feature = "binary"))]
+    pub const CURRENT: Encoding = Encoding::Json;
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::Bincode => "bincode",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "json" => Some(Encoding::Json),
+            "bincode" => Some(Encoding::Bincode),
+            _ => None,
+        }
+    }
+
+    /// Whether this build can read and write records in this encoding.
+    pub fn is_supported(self) -> bool {
+        self == Encoding::Json || cfg!(feature = "binary")
+    }
+
+    /// The encoding `bytes`, a stored record, were written in.
+    pub fn of(bytes: &[u8]) -> Result<Encoding, Error> {
+        match bytes.first() {
+            Some(b'{') => Ok(Encoding::Json),
+            Some(&BINCODE_TAG) => Ok(Encoding::Bincode),
+            first => Err(Error::Unknown(first.copied().unwrap_or(0))),
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, Error> {
+        match self {
+            Encoding::Json => Ok(serde_json::to_vec(value)?),
+            #[cfg(feature = "binary")]
+            Encoding::Bincode => {
+                let mut bytes = vec![BINCODE_TAG];
+                bincode::serialize_into(&mut bytes, value)?;
+                Ok(bytes)
+            }
+            #[cfg(not(feature = "binary"))]
+            Encoding::Bincode => Err(Error::Unsupported(self.name())),
+        }
+    }
+}
+
+/// Reads a record in whichever encoding it was written in.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+    match Encoding::of(bytes)? {
+        Encoding::Json => Ok(serde_json::from_slice(bytes)?),
+        #[cfg(feature = "binary")]
+        Encoding::Bincode => Ok(bincode::deserialize(&bytes[1..])?),
+        #[cfg(not(feature = "binary"))]
+        Encoding::Bincode => Err(Error::Unsupported(Encoding::Bincode.name())),
+    }
+}
+
+/// A value in a collection's bucket: a record, or the empty marker of an index entry,
+/// whose key says all there is to say.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Entry<T> {
+    Record(T),
+    Marker,
+}
+
+impl<T> Entry<T> {
+    pub fn into_record(self) -> Result<T, Error> {
+        match self {
+            Entry::Record(record) => Ok(record),
+            Entry::Marker => Err(Error::Marker),
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Value for Entry<T> {
+    /// Records are written in `Encoding::CURRENT`.
+    fn to_raw_value(&self) -> Result<Raw, kv::Error> {
+        match self {
+            Entry::Record(record) => Ok(Raw::from(Encoding::CURRENT.encode(record).map_err(message)?)),
+            Entry::Marker => Ok(Raw::from(&[][..])),
+        }
+    }
+
+    fn from_raw_value(raw: Raw) -> Result<Self, kv::Error> {
+        if raw.is_empty() {
+            return Ok(Entry::Marker);
+        }
+        decode(&raw).map(Entry::Record).map_err(message)
+    }
+}
+
+fn message(e: Error) -> kv::Error {
+    kv::Error::Message(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Sample {
+        path: String,
+        size: u64,
+    }
+
+    fn sample() -> Sample {
+        Sample {
+            path: String::from("/src/monitor.rs"),
+            size: 4096,
+        }
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let record = Entry::Record(sample());
+        let raw = record.to_raw_value().unwrap();
+        assert_eq!(Encoding::of(&raw).unwrap(), Encoding::CURRENT);
+        assert_eq!(Entry::from_raw_value(raw).unwrap(), record);
+
+        let marker: Entry<Sample> = Entry::Marker;
+        let raw = marker.to_raw_value().unwrap();
+        assert!(raw.is_empty());
+        assert_eq!(Entry::from_raw_value(raw).unwrap(), marker);
+        assert!(matches!(marker.into_record(), Err(Error::Marker)));
+    }
+
+    #[test]
+    fn json_records_are_read_by_every_build() {
+        let raw = Raw::from(&br#"{"path":"/src/monitor.rs","size":4096}"#[..]);
+        assert_eq!(Entry::from_raw_value(raw).unwrap(), Entry::Record(sample()));
+        assert!(matches!(decode::<Sample>(b"\xff"), Err(Error::Unknown(0xff))));
+    }
+
+    #[cfg(feature = "binary")]
+    #[test]
+    fn bincode_is_smaller_than_json() {
+        let json = Encoding::Json.encode(&sample()).unwrap();
+        let binary = Encoding::Bincode.encode(&sample()).unwrap();
+        assert!(binary.len() < json.len());
+        assert_eq!(decode::<Sample>(&binary).unwrap(), sample());
+    }
+
+    #[cfg(not(feature = "binary"))]
+    #[test]
+    fn bincode_needs_the_feature() {
+        assert!(!Encoding::Bincode.is_supported());
+        assert!(matches!(Encoding::Bincode.encode(&sample()), Err(Error::Unsupported("bincode"))));
+        assert!(matches!(decode::<Sample>(&[BINCODE_TAG, 0]), Err(Error::Unsupported("bincode"))));
+    }
+}
@@ -0,0 +1,408 @@
+use crate::exclude::{Error as ExcludeError, Excludes};
+use ignore::gitignore::GitignoreBuilder;
+use log::{warn, LevelFilter};
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use thiserror::Error as TError;
+
+/// The system-wide configuration file, read before the user's.
+pub const SYSTEM_CONFIG: &str = "/etc/quind/config.toml";
+
+/// The longest debounce window accepted, in milliseconds.
+const MAX_DEBOUNCE_MS: u64 = 60_000;
+
+#[derive(Debug, TError)]
+pub enum Error {
+    #[error("{}: {source}", path.display())]
+    Read { path: PathBuf, source: io::Error },
+
+    #[error("{}: {source}", path.display())]
+    Parse { path: PathBuf, source: toml::de::Error },
+
+    #[error("{}: {field}: {message}", path.display())]
+    Invalid { path: PathBuf, field: String, message: String },
+}
+
+/// A watched root and the options that apply to it alone.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Root {
+    pub path: PathBuf,
+    /// Gitignore-style patterns applied below this root only.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Whether `watch` follows this root; if not it is only crawled by `index`.
+    #[serde(default = "yes")]
+    pub watch: bool,
+    /// Collection holding this root's records, which roots may share; the root's own
+    /// path when unset.
+    #[serde(default)]
+    pub collection: Option<String>,
+}
+
+fn yes() -> bool {
+    true
+}
+
+/// One configuration file as written.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Layer {
+    db: Option<PathBuf>,
+    socket: Option<PathBuf>,
+    log_level: Option<String>,
+    debounce_ms: Option<u64>,
+    precise: Option<bool>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(default)]
+    root: Vec<Root>,
+}
+
+/// Settings read from TOML files such as:
+///
+/// ```toml
+/// db = "/var/lib/quind"
+/// socket = "/run/quind.sock"
+/// log_level = "info"
+/// debounce_ms = 200
+/// precise = true
+/// exclude = ["*.o", "node_modules/"]
+///
+/// [[root]]
+/// path = "~/src"
+/// exclude = ["target/"]
+///
+/// [[root]]
+/// path = "/mnt/archive"
+/// watch = false
+/// ```
+///
+/// Files are read in order and later ones win: settings replace earlier values,
+/// excludes add up, and a root listed again replaces its earlier entry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub db: Option<PathBuf>,
+    /// Where the daemon answers queries; next to the database if not set.
+    pub socket: Option<PathBuf>,
+    pub log_level: LevelFilter,
+    pub debounce: Duration,
+    pub precise: bool,
+    pub exclude: Vec<String>,
+    pub roots: Vec<Root>,
+    sources: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            db: None,
+            socket: None,
+            log_level: LevelFilter::Warn,
+            debounce: Duration::from_millis(200),
+            precise: false,
+            exclude: Vec::new(),
+            roots: Vec::new(),
+            sources: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// The system-wide file, then the user's under `$XDG_CONFIG_HOME` or `~/.config`.
+    pub fn default_paths() -> Vec<PathBuf> {
+        let mut paths = vec![PathBuf::from(SYSTEM_CONFIG)];
+        match (env::var_os("XDG_CONFIG_HOME"), env::var_os("HOME")) {
+            (Some(config), _) => paths.push(PathBuf::from(config).join("quind/config.toml")),
+            (None, Some(home)) => paths.push(PathBuf::from(home).join(".config/quind/config.toml")),
+            (None, None) => {}
+        }
+        paths
+    }
+
+    /// Reads and checks the files in `paths` that exist, in order.
+    pub fn load(paths: &[PathBuf]) -> Result<Config, Error> {
+        let mut config = Config::default();
+        for path in paths {
+            let text = match fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    config.sources.push((path.clone(), None));
+                    continue;
+                }
+                Err(source) => return Err(Error::Read { path: path.clone(), source }),
+            };
+            let layer = toml::from_str(&text).map_err(|source| Error::Parse { path: path.clone(), source })?;
+            config.merge(path, layer)?;
+            config.sources.push((path.clone(), modified(path)));
+        }
+        Ok(config)
+    }
+
+    /// The files this configuration was read from, or looked for.
+    pub fn sources(&self) -> Vec<PathBuf> {
+        self.sources.iter().map(|(p, _)| p.clone()).collect()
+    }
+
+    /// Whether any of the files was created, changed or removed since it was read.
+    pub fn changed(&self) -> bool {
+        self.sources.iter().any(|(p, seen)| modified(p) != *seen)
+    }
+
+    /// Takes the files as they are now as read, so a change that failed to load is not
+    /// reported again until the files change once more.
+    pub fn mark_read(&mut self) {
+        for (path, seen) in &mut self.sources {
+            *seen = modified(path);
+        }
+    }
+
+    /// The configured roots that can be found on disk, only those to be watched if
+    /// `watching`, with symbolic links resolved. The others are logged and left out.
+    pub fn live_roots(&self, watching: bool) -> Vec<PathBuf> {
+        let mut roots = Vec::new();
+        for root in self.roots.iter().filter(|r| r.watch || !watching) {
+            match fs::canonicalize(&root.path) {
+                Ok(p) => roots.push(p),
+                Err(e) => warn!("{}: {}", root.path.display(), e),
+            }
+        }
+        roots
+    }
+
+    /// The configured root holding `path`, if any.
+    pub fn root(&self, path: &Path) -> Option<&Root> {
+        self.roots.iter().filter(|r| path.starts_with(&r.path)).max_by_key(|r| r.path.as_os_str().len())
+    }
+
+    /// Name of the collection holding the records of `root`: the one configured for it,
+    /// or else its path.
+    pub fn collection(&self, root: &Path) -> String {
+        let configured = self
+            .roots
+            .iter()
+            .find(|r| r.path == root || fs::canonicalize(&r.path).is_ok_and(|p| p == root));
+        match configured.and_then(|r| r.collection.clone()) {
+            Some(name) => name,
+            None => root.display().to_string(),
+        }
+    }
+
+    /// Exclusion rules from the ignore files, the `global` file, and these settings.
+    pub fn excludes(&self, global: Option<&Path>) -> Result<Excludes, ExcludeError> {
+        let mut excludes = Excludes::new(global)?;
+        excludes.add_patterns(None, &self.exclude)?;
+        for root in &self.roots {
+            // Crawls and events see the root's canonical path, through any symlink.
+            let path = fs::canonicalize(&root.path).unwrap_or_else(|_| root.path.clone());
+            excludes.add_patterns(Some(&path), &root.exclude)?;
+        }
+        Ok(excludes)
+    }
+
+    fn merge(&mut self, path: &Path, layer: Layer) -> Result<(), Error> {
+        let invalid = |field: String, message: String| Error::Invalid {
+            path: path.to_path_buf(),
+            field,
+            message,
+        };
+        if let Some(db) = layer.db {
+            self.db = Some(expand(&db).ok_or_else(|| invalid("db".into(), "must be an absolute path".into()))?);
+        }
+        if let Some(socket) = layer.socket {
+            self.socket = Some(expand(&socket).ok_or_else(|| invalid("socket".into(), "must be an absolute path".into()))?);
+        }
+        if let Some(level) = layer.log_level {
+            self.log_level = level.parse().map_err(|_| {
+                invalid(
+                    "log_level".into(),
+                    format!("'{}' is not one of off, error, warn, info, debug, trace", level),
+                )
+            })?;
+        }
+        if let Some(ms) = layer.debounce_ms {
+            if ms > MAX_DEBOUNCE_MS {
+                let message = format!("{} is more than {} milliseconds", ms, MAX_DEBOUNCE_MS);
+                return Err(invalid("debounce_ms".into(), message));
+            }
+            self.debounce = Duration::from_millis(ms);
+        }
+        if let Some(precise) = layer.precise {
+            self.precise = precise;
+        }
+        check_patterns(&layer.exclude).map_err(|e| invalid("exclude".into(), e))?;
+        self.exclude.extend(layer.exclude);
+
+        let mut seen = Vec::new();
+        for (i, mut root) in layer.root.into_iter().enumerate() {
+            let field = |name: &str| format!("root[{}].{}", i, name);
+            root.path = expand(&root.path).ok_or_else(|| invalid(field("path"), "must be an absolute path".into()))?;
+            if seen.contains(&root.path) {
+                let message = format!("{} is listed twice", root.path.display());
+                return Err(invalid(field("path"), message));
+            }
+            check_patterns(&root.exclude).map_err(|e| invalid(field("exclude"), e))?;
+            if root.collection.as_deref().is_some_and(|c| c.trim().is_empty()) {
+                return Err(invalid(field("collection"), String::from("must not be empty")));
+            }
+            seen.push(root.path.clone());
+            match self.roots.iter_mut().find(|r| r.path == root.path) {
+                Some(old) => *old = root,
+                None => self.roots.push(root),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `path` with a leading `~` replaced by the home directory, if it is then absolute.
+fn expand(path: &Path) -> Option<PathBuf> {
+    let path = match (path.strip_prefix("~"), env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    };
+    match path.is_absolute() {
+        true => Some(path),
+        false => None,
+    }
+}
+
+fn check_patterns(lines: &[String]) -> Result<(), String> {
+    let mut builder = GitignoreBuilder::new("/");
+    for line in lines {
+        if let Err(e) = builder.add_line(None, line) {
+            return Err(format!("'{}': {}", line, e));
+        }
+    }
+    builder.build().map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    fn reset(name: &str) -> PathBuf {
+        let p = Path::new("./instance").join(name);
+        let _ = fs::remove_dir_all(&p);
+        fs::create_dir_all(&p).unwrap();
+        fs::canonicalize(p).unwrap()
+    }
+
+    fn write(dir: &Path, name: &str, text: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn error(text: &str) -> String {
+        let dir = reset("config_errors");
+        let path = write(&dir, "config.toml", text);
+        Config::load(&[path]).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn missing_files_give_defaults() {
+        let dir = reset("config_missing");
+        let config = Config::load(&[dir.join("none.toml")]).unwrap();
+        assert_eq!(config.log_level, LevelFilter::Warn);
+        assert_eq!(config.debounce, Duration::from_millis(200));
+        assert!(config.roots.is_empty());
+        assert_eq!(config.sources(), vec![dir.join("none.toml")]);
+    }
+
+    #[test]
+    fn later_files_win() {
+        let dir = reset("config_layers");
+        let system = write(
+            &dir,
+            "system.toml",
+            "db = \"/var/lib/quind\"\nlog_level = \"error\"\nexclude = [\"*.o\"]\n\
+             [[root]]\npath = \"/srv\"\n[[root]]\npath = \"/mnt\"\n",
+        );
+        let user = write(
+            &dir,
+            "user.toml",
+            "log_level = \"debug\"\ndebounce_ms = 0\nprecise = true\nexclude = [\"target/\"]\n\
+             [[root]]\npath = \"/mnt\"\nwatch = false\nexclude = [\"tmp/\"]\ncollection = \"media\"\n",
+        );
+        let config = Config::load(&[system, user]).unwrap();
+
+        assert_eq!(config.db, Some(PathBuf::from("/var/lib/quind")));
+        assert_eq!(config.log_level, LevelFilter::Debug);
+        assert_eq!(config.debounce, Duration::from_millis(0));
+        assert!(config.precise);
+        assert_eq!(config.exclude, vec!["*.o", "target/"]);
+        assert_eq!(config.roots.len(), 2);
+        let mnt = config.root(Path::new("/mnt/disk/a")).unwrap();
+        assert!(!mnt.watch);
+        assert_eq!(mnt.exclude, vec!["tmp/"]);
+        assert!(config.root(Path::new("/srv")).unwrap().watch);
+        assert!(config.root(Path::new("/home")).is_none());
+        assert_eq!(config.collection(Path::new("/mnt")), "media");
+        assert_eq!(config.collection(Path::new("/srv")), "/srv");
+
+        let excludes = config.excludes(None).unwrap();
+        assert!(excludes.is_excluded(Path::new("/srv/a.o"), false));
+        assert!(excludes.is_excluded(Path::new("/mnt/tmp/x"), false));
+        assert!(!excludes.is_excluded(Path::new("/srv/tmp/x"), false));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn root_patterns_follow_symlinks() {
+        let dir = reset("config_symlink");
+        fs::create_dir_all(dir.join("data/src")).unwrap();
+        std::os::unix::fs::symlink(dir.join("data/src"), dir.join("src")).unwrap();
+        let text = format!("[[root]]\npath = \"{}\"\nexclude = [\"*.tmp\"]\n", dir.join("src").display());
+        let config = Config::load(&[write(&dir, "config.toml", &text)]).unwrap();
+
+        let excludes = config.excludes(None).unwrap();
+        assert!(excludes.is_excluded(&dir.join("data/src/x.tmp"), false));
+        assert!(!excludes.is_excluded(&dir.join("data/src/x.rs"), false));
+    }
+
+    #[test]
+    fn errors_name_the_file_and_field() {
+        assert!(error("log_level = \"loud\"").contains("log_level: 'loud' is not one of"));
+        assert!(error("debounce_ms = 600000").contains("debounce_ms"));
+        assert!(error("exclude = [\"[z-a]\"]").contains("exclude: '[z-a]'"));
+        assert!(error("[[root]]\npath = \"src\"").contains("root[0].path: must be an absolute path"));
+        assert!(error("[[root]]\npath = \"/a\"\n[[root]]\npath = \"/a\"").contains("root[1].path: /a is listed twice"));
+        assert!(error("[[root]]\npath = \"/a\"\ncollection = \"\"").contains("root[0].collection: must not be empty"));
+        let unknown = error("debounce = 5");
+        assert!(unknown.contains("config.toml") && unknown.contains("unknown field `debounce`"));
+        assert!(error("precise = \"yes\"").contains("line 1"));
+    }
+
+    #[test]
+    fn changes_are_noticed() {
+        let dir = reset("config_changes");
+        let path = write(&dir, "config.toml", "precise = true\n");
+        let config = Config::load(&[path.clone(), dir.join("later.toml")]).unwrap();
+        assert!(!config.changed());
+
+        write(&dir, "later.toml", "precise = false\n");
+        assert!(config.changed());
+        let config = Config::load(&config.sources()).unwrap();
+        assert!(!config.precise);
+        assert!(!config.changed());
+
+        thread::sleep(Duration::from_millis(20));
+        write(&dir, "config.toml", "precise = true\n");
+        fs::remove_file(dir.join("later.toml")).unwrap();
+        assert!(config.changed());
+        let mut config = config;
+        config.mark_read();
+        assert!(!config.changed());
+    }
+}
@@ -0,0 +1,507 @@
+use crate::config::Config;
+use crate::error::Error;
+use crate::exclude::Excludes;
+use crate::fdb::{Fdb, IndexStats};
+use crate::ipc::{Call, Response};
+use crate::monitor::Monitor;
+#[cfg(unix)]
+use crate::search::Query;
+use log::{info, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// How long the daemon waits for events before looking at its flags again.
+const TICK: Duration = Duration::from_millis(250);
+
+/// How often the configuration files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The most results one answer over the socket carries; clients follow the `next`
+/// cursor for the rest.
+const PAGE_SIZE: usize = 1000;
+
+/// Settings from the command line, which a configuration reload leaves alone.
+#[derive(Debug, Default)]
+pub struct Options {
+    /// Roots to watch instead of the configured ones.
+    pub roots: Option<Vec<PathBuf>>,
+    /// Debounce window to use instead of the configured one.
+    pub debounce: Option<Duration>,
+    /// The global ignore file.
+    pub ignore: Option<PathBuf>,
+    /// Where to answer queries from other processes, if anywhere.
+    pub socket: Option<PathBuf>,
+}
+
+/// A request that changes what is watched, passed to the thread running the daemon
+/// along with where to send the answer.
+type Command = (Call, mpsc::Sender<Response>);
+
+/// Owns the `Fdb` and the `Monitor` that keeps it up to date, from the first crawl of
+/// each root until it is asked to stop.
+///
+/// Configuration files are read again when they change or when `reload` is raised,
+/// and on `stop` the events still held back are applied and the store flushed before
+/// `run` returns.
+///
+/// With a socket, searches are answered straight from the store on the server's
+/// threads, while status and root changes are handed to the daemon's own thread
+/// between batches of events. Roots added or removed that way last until it stops.
+pub struct Daemon {
+    fdb: Arc<RwLock<Fdb>>,
+    monitor: Monitor,
+    config: Config,
+    options: Options,
+    roots: Vec<PathBuf>,
+    added: Vec<PathBuf>,
+    removed: Vec<PathBuf>,
+    stop: Arc<AtomicBool>,
+    reload: Arc<AtomicBool>,
+}
+
+impl Daemon {
+    pub fn new(fdb: Fdb, config: Config, excludes: Arc<Excludes>, options: Options) -> Daemon {
+        let mut monitor = Monitor::new();
+        monitor.set_excludes(excludes);
+        if config.precise {
+            if let Err(e) = monitor.set_precise() {
+                warn!("precise events: {}", e);
+            }
+        }
+        let window = options.debounce.unwrap_or(config.debounce);
+        if window > Duration::from_millis(0) {
+            monitor.debounce(window);
+        }
+        let roots = match &options.roots {
+            Some(roots) => roots.clone(),
+            None => config.live_roots(true),
+        };
+        Daemon {
+            fdb: Arc::new(RwLock::new(fdb)),
+            monitor,
+            config,
+            options,
+            roots,
+            added: Vec::new(),
+            removed: Vec::new(),
+            stop: Arc::new(AtomicBool::new(false)),
+            reload: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Raising this flag makes `run` finish up and return.
+    pub fn stop_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.stop)
+    }
+
+    /// Raising this flag makes `run` read the configuration again.
+    pub fn reload_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.reload)
+    }
+
+    /// Stops on SIGINT or SIGTERM and reloads on SIGHUP. A second SIGINT or SIGTERM
+    /// while stopping exits at once.
+    pub fn handle_signals(&self) -> Result<(), Error> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        use signal_hook::flag;
+
+        for &signal in &[SIGINT, SIGTERM] {
+            flag::register_conditional_shutdown(signal, 1, Arc::clone(&self.stop))?;
+            flag::register(signal, Arc::clone(&self.stop))?;
+        }
+        #[cfg(unix)]
+        flag::register(signal_hook::consts::SIGHUP, Arc::clone(&self.reload))?;
+        Ok(())
+    }
+
+    /// Watches every root and applies its changes until stopped.
+    pub fn run(mut self) -> Result<(), Error> {
+        let (tx, commands) = mpsc::channel();
+        let serving = match &self.options.socket {
+            Some(path) => Some(self.listen(path, tx)?),
+            None => None,
+        };
+        let result = self.follow(&commands);
+        #[cfg(unix)]
+        {
+            if let Some(serving) = serving {
+                serving.stop();
+            }
+        }
+        #[cfg(not(unix))]
+        drop(serving);
+        result?;
+        info!("stopping");
+        self.fdb.read().unwrap().finish(&mut self.monitor)?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn listen(&self, path: &Path, commands: mpsc::Sender<Command>) -> Result<crate::ipc::Serving, Error> {
+        let server = crate::ipc::Server::bind(path)?;
+        let fdb = Arc::clone(&self.fdb);
+        let commands = std::sync::Mutex::new(commands);
+        Ok(server.spawn(move |call| match call {
+            Call::Search { query } => search(&fdb.read().unwrap(), query, PAGE_SIZE),
+            call => {
+                let (tx, rx) = mpsc::channel();
+                let sent = commands.lock().unwrap().send((call, tx));
+                match sent.ok().and_then(|_| rx.recv().ok()) {
+                    Some(response) => response,
+                    None => failure(Error::Usage(String::from("the daemon is stopping"))),
+                }
+            }
+        }))
+    }
+
+    #[cfg(not(unix))]
+    fn listen(&self, _path: &Path, _commands: mpsc::Sender<Command>) -> Result<(), Error> {
+        Err(Error::Usage(String::from("sockets are only supported on Unix")))
+    }
+
+    fn follow(&mut self, commands: &mpsc::Receiver<Command>) -> Result<(), Error> {
+        for root in self.roots.clone() {
+            self.monitor.watch(&root)?;
+            refresh(&self.fdb.read().unwrap(), &root, &self.config.collection(&root))?;
+        }
+        let mut checked = Instant::now();
+        while !self.stop.load(Ordering::SeqCst) {
+            self.fdb.read().unwrap().follow_for(&mut self.monitor, TICK)?;
+            while let Ok((call, tx)) = commands.try_recv() {
+                let response = self.command(call).unwrap_or_else(failure);
+                let _ = tx.send(response);
+            }
+            let forced = self.reload.swap(false, Ordering::SeqCst);
+            if forced || checked.elapsed() >= RELOAD_INTERVAL {
+                checked = Instant::now();
+                if forced || self.config.changed() {
+                    self.reload_config(forced)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, call: Call) -> Result<Response, Error> {
+        let fdb = self.fdb.read().unwrap();
+        match call {
+            Call::Status => Ok(Response::Status {
+                db: fdb.path.clone(),
+                entries: fdb.count()?,
+                roots: self.roots.clone(),
+            }),
+            Call::AddRoot { path, collection } => {
+                let root = fs::canonicalize(&path).map_err(|e| Error::Usage(format!("{}: {}", path.display(), e)))?;
+                let collection = collection.unwrap_or_else(|| self.config.collection(&root));
+                fdb.register(&root, &collection)?;
+                let stats = match self.roots.contains(&root) {
+                    true => IndexStats::default(),
+                    false => {
+                        self.monitor.watch(&root)?;
+                        self.roots.push(root.clone());
+                        fdb.refresh(&root)?
+                    }
+                };
+                self.removed.retain(|r| r != &root);
+                self.added.push(root.clone());
+                info!("{}: added, {} entries indexed", root.display(), stats.added);
+                Ok(Response::Added { root, stats })
+            }
+            Call::Index { path, collection } => {
+                let root = fs::canonicalize(&path).map_err(|e| Error::Usage(format!("{}: {}", path.display(), e)))?;
+                let collection = collection.unwrap_or_else(|| self.config.collection(&root));
+                fdb.register(&root, &collection)?;
+                let stats = fdb.index_tree(&root)?;
+                info!("{}: indexed, {} entries added", root.display(), stats.added);
+                Ok(Response::Indexed { root, stats })
+            }
+            Call::RemoveRoot { path } => {
+                let root = fs::canonicalize(&path).unwrap_or(path);
+                if self.roots.contains(&root) {
+                    self.monitor.unwatch(&root)?;
+                    self.roots.retain(|r| r != &root);
+                }
+                let entries = fdb.forget(&root)?;
+                self.added.retain(|r| r != &root);
+                self.removed.push(root.clone());
+                info!("{}: removed, {} entries dropped", root.display(), entries);
+                Ok(Response::Removed { root, entries })
+            }
+            Call::Search { .. } => Err(Error::Usage(String::from("searches are answered by the server"))),
+        }
+    }
+
+    fn reload_config(&mut self, forced: bool) -> Result<(), Error> {
+        match Config::load(&self.config.sources()) {
+            Ok(new) => {
+                self.apply(new, forced)?;
+            }
+            Err(e) => {
+                warn!("{}; keeping the previous configuration", e);
+                self.config.mark_read();
+            }
+        }
+        Ok(())
+    }
+
+    /// Switches to `new`. Roots are watched or unwatched as listed, those no longer
+    /// listed at all are forgotten, and new exclusion rules are applied by crawling
+    /// every root again. `forced` rules are rebuilt even if the settings look the same,
+    /// to pick up a changed global ignore file.
+    fn apply(&mut self, new: Config, forced: bool) -> Result<(), Error> {
+        info!("reloading the configuration");
+        let old = &self.config;
+        log::set_max_level(new.log_level);
+        if new.db != old.db {
+            warn!("the new database location is used after a restart");
+        }
+        if new.precise && !old.precise {
+            if let Err(e) = self.monitor.set_precise() {
+                warn!("precise events: {}", e);
+            }
+        } else if old.precise && !new.precise {
+            warn!("precise events stay on until a restart");
+        }
+        if self.options.debounce.is_none() && new.debounce != old.debounce {
+            self.monitor.debounce(new.debounce);
+        }
+
+        let rules = |c: &Config| {
+            let roots: Vec<(PathBuf, Vec<String>)> = c.roots.iter().map(|r| (r.path.clone(), r.exclude.clone())).collect();
+            (c.exclude.clone(), roots)
+        };
+        let mut recrawl = false;
+        if forced || rules(old) != rules(&new) {
+            match new.excludes(self.options.ignore.as_deref()) {
+                Ok(excludes) => {
+                    let excludes = Arc::new(excludes);
+                    self.fdb.write().unwrap().set_excludes(Arc::clone(&excludes));
+                    self.monitor.set_excludes(excludes);
+                    recrawl = true;
+                }
+                Err(e) => warn!("{}; keeping the previous exclusion rules", e),
+            }
+        }
+
+        let mut wanted = match &self.options.roots {
+            Some(roots) => roots.clone(),
+            None => new.live_roots(true),
+        };
+        wanted.extend(self.added.iter().filter(|r| !wanted.contains(r)).cloned().collect::<Vec<_>>());
+        wanted.retain(|r| !self.removed.contains(r));
+        let added = &self.added;
+        let configured = |root: &PathBuf| {
+            let listed = new.roots.iter().any(|r| &r.path == root || fs::canonicalize(&r.path).ok().as_ref() == Some(root));
+            listed || added.contains(root)
+        };
+        let fdb = self.fdb.read().unwrap();
+        for root in &self.roots {
+            if recrawl || !wanted.contains(root) {
+                if let Err(e) = self.monitor.unwatch(root) {
+                    warn!("{}: {}", root.display(), e);
+                }
+            }
+            // A root gone from the configuration takes its records with it, as one
+            // removed over the socket does; what another root still covers is crawled
+            // again into that root's collection.
+            if self.options.roots.is_none() && !configured(root) {
+                match fdb.forget(root) {
+                    Ok(entries) => info!("{}: no longer configured, {} entries dropped", root.display(), entries),
+                    Err(e) => warn!("{}: {}", root.display(), e),
+                }
+                if wanted.iter().any(|w| root.starts_with(w)) {
+                    if let Err(e) = fdb.index_tree(root) {
+                        warn!("{}: {}", root.display(), e);
+                    }
+                }
+            }
+        }
+        for root in &wanted {
+            // Roots added over the socket keep the collection they were given.
+            if !self.added.contains(root) {
+                if let Err(e) = fdb.register(root, &new.collection(root)) {
+                    warn!("{}: {}", root.display(), e);
+                }
+            }
+            let known = self.roots.contains(root);
+            if known && !recrawl {
+                continue;
+            }
+            if let Err(e) = self.monitor.watch(root) {
+                warn!("{}: {}", root.display(), e);
+                continue;
+            }
+            match known {
+                true => {
+                    let stats = fdb.reconcile(root)?;
+                    info!(
+                        "{}: recrawled, {} added, {} removed, {} failed",
+                        root.display(),
+                        stats.added,
+                        stats.removed,
+                        stats.failed
+                    );
+                }
+                false => refresh(&fdb, root, &new.collection(root))?,
+            }
+        }
+        drop(fdb);
+        self.roots = wanted;
+        self.config = new;
+        Ok(())
+    }
+}
+
+/// The answer for a request that failed with `e`.
+fn failure(e: Error) -> Response {
+    Response::Error {
+        message: e.to_string(),
+        code: e.exit_code(),
+    }
+}
+
+/// Answers a search with at most `most` results, so an unlimited query cannot build
+/// one answer the size of the whole store.
+#[cfg(unix)]
+fn search(fdb: &Fdb, query: Query, most: usize) -> Response {
+    let limit = query.limit.map_or(most, |n| n.min(most));
+    match fdb.page(&query.limit(limit)) {
+        Ok(page) => Response::Files { files: page.files, next: page.next },
+        Err(e) => failure(Error::DB(e)),
+    }
+}
+
+/// Catches up with changes made under `root`, kept in `collection`, while nothing was
+/// watching; events that arrive meanwhile are queued and applied afterwards.
+fn refresh(fdb: &Fdb, root: &Path, collection: &str) -> Result<(), Error> {
+    fdb.register(root, collection)?;
+    let stats = fdb.refresh(root)?;
+    info!(
+        "{}: {} added, {} removed, {} failed",
+        root.display(),
+        stats.added,
+        stats.removed,
+        stats.failed
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::Root;
+    use std::fs;
+    use std::thread;
+
+    fn reset(name: &str) -> PathBuf {
+        let p = Path::new("./instance").join(name);
+        let _ = fs::remove_dir_all(&p);
+        fs::create_dir_all(&p).unwrap();
+        fs::canonicalize(p).unwrap()
+    }
+
+    #[test]
+    fn stopping_applies_held_events() {
+        let db = reset("daemon_db");
+        let root = reset("daemon_root");
+        let fdb = Fdb::new(&db, String::from("test")).unwrap();
+        let options = Options {
+            roots: Some(vec![root.clone()]),
+            // Long enough that the change is still held back when the daemon stops.
+            debounce: Some(Duration::from_secs(30)),
+            ignore: None,
+            socket: None,
+        };
+        let daemon = Daemon::new(fdb, Config::default(), Arc::new(Excludes::new(None).unwrap()), options);
+        let stop = daemon.stop_flag();
+        let running = thread::spawn(move || daemon.run());
+
+        thread::sleep(Duration::from_millis(300));
+        fs::write(root.join("late.txt"), "").unwrap();
+        thread::sleep(Duration::from_millis(300));
+        stop.store(true, Ordering::SeqCst);
+        running.join().unwrap().unwrap();
+
+        let fdb = Fdb::new(&db, String::from("test")).unwrap();
+        assert!(fdb.check("late.txt").unwrap());
+    }
+
+    #[test]
+    fn indexing_over_the_socket_does_not_watch() {
+        let db = reset("daemon_index_db");
+        let root = reset("daemon_index_root");
+        fs::write(root.join("once.txt"), "").unwrap();
+        let fdb = Fdb::new(&db, String::from("test")).unwrap();
+        let options = Options { roots: Some(Vec::new()), debounce: None, ignore: None, socket: None };
+        let mut daemon = Daemon::new(fdb, Config::default(), Arc::new(Excludes::new(None).unwrap()), options);
+
+        let call = Call::Index { path: root.clone(), collection: Some(String::from("once")) };
+        match daemon.command(call).unwrap() {
+            Response::Indexed { root: indexed, stats } => {
+                assert_eq!(indexed, root);
+                // The root and the file in it.
+                assert_eq!(stats.added, 2);
+            }
+            response => panic!("unexpected {:?}", response),
+        }
+        assert!(daemon.roots.is_empty() && daemon.added.is_empty());
+        assert!(daemon.monitor.roots_of(&[root.join("once.txt")]).is_empty());
+        assert!(daemon.fdb.read().unwrap().check("once.txt").unwrap());
+    }
+    #[test]
+    fn reloading_forgets_roots_dropped_from_the_config() {
+        let db = reset("daemon_reload_db");
+        let (kept, dropped) = (reset("daemon_reload_kept"), reset("daemon_reload_dropped"));
+        fs::write(kept.join("kept.txt"), "").unwrap();
+        fs::write(dropped.join("dropped.txt"), "").unwrap();
+        let root = |path: &PathBuf| Root { path: path.clone(), exclude: Vec::new(), watch: true, collection: None };
+        let mut config = Config::default();
+        config.roots = vec![root(&kept), root(&dropped)];
+        let fdb = Fdb::new(&db, String::from("test")).unwrap();
+        fdb.index_tree(&kept).unwrap();
+        fdb.index_tree(&dropped).unwrap();
+        let options = Options { roots: None, debounce: None, ignore: None, socket: None };
+        let mut daemon = Daemon::new(fdb, config.clone(), Arc::new(Excludes::new(None).unwrap()), options);
+
+        config.roots.pop();
+        daemon.apply(config, false).unwrap();
+        assert_eq!(daemon.roots, vec![kept]);
+        let fdb = daemon.fdb.read().unwrap();
+        assert!(fdb.check("kept.txt").unwrap());
+        assert!(!fdb.check("dropped.txt").unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn searches_are_answered_in_pages() {
+        let db = reset("daemon_page_db");
+        let root = reset("daemon_page_root");
+        for i in 0..5 {
+            fs::write(root.join(format!("page{}.txt", i)), "").unwrap();
+        }
+        let fdb = Fdb::new(&db, String::from("test")).unwrap();
+        fdb.index_tree(&root).unwrap();
+
+        let mut query = Query::new(".txt");
+        let mut seen = Vec::new();
+        loop {
+            match search(&fdb, query.clone(), 2) {
+                Response::Files { files, next } => {
+                    assert!(files.len() <= 2);
+                    seen.extend(files.into_iter().map(|f| f.name));
+                    match next {
+                        Some(cursor) => query = query.after(cursor),
+                        None => break,
+                    }
+                }
+                response => panic!("unexpected {:?}", response),
+            }
+        }
+        seen.sort();
+        assert_eq!(seen, (0..5).map(|i| format!("page{}.txt", i)).collect::<Vec<_>>());
+    }
+}
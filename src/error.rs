@@ -1,8 +1,24 @@
+use crate::config::Error as ConfigError;
+use crate::exclude::Error as ExcludeError;
 use crate::fdb::Error as FdbError;
+use crate::ipc::Error as IpcError;
 use crate::monitor::Error as MonitorError;
 use std::io;
 use thiserror::Error as TError;
 
+/// Exit status of a successful `quind` run.
+pub const EXIT_OK: i32 = 0;
+/// Exit status when a search matched nothing, as `locate` does.
+pub const EXIT_NOT_FOUND: i32 = 1;
+/// Exit status for invalid command line usage.
+pub const EXIT_USAGE: i32 = 2;
+/// Exit status for filesystem failures.
+pub const EXIT_IO: i32 = 3;
+/// Exit status for database failures.
+pub const EXIT_DB: i32 = 4;
+/// Exit status for watcher failures.
+pub const EXIT_MONITOR: i32 = 5;
+
 #[derive(Debug, TError)]
 pub enum Error {
     #[error("IO error: {0}")]
@@ -13,4 +29,37 @@ pub enum Error {
 
     #[error("Monitor error: {0}")]
     Monitor(#[from] MonitorError),
+
+    #[error("Config error: {0}")]
+    Config(#[from] ConfigError),
+
+    #[error("Exclude error: {0}")]
+    Exclude(#[from] ExcludeError),
+
+    #[error("Daemon error: {0}")]
+    Ipc(#[from] IpcError),
+
+    #[error("Usage error: {0}")]
+    Usage(String),
+}
+
+impl Error {
+    /// The process exit status the CLI reports for this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::IO(_) => EXIT_IO,
+            Error::DB(FdbError::Monitor(_)) => EXIT_MONITOR,
+            Error::DB(FdbError::Glob(_))
+            | Error::DB(FdbError::Regex(_))
+            | Error::DB(FdbError::Filter(_))
+            | Error::DB(FdbError::Cursor(_))
+            | Error::DB(FdbError::Collection(_)) => EXIT_USAGE,
+            Error::DB(_) => EXIT_DB,
+            Error::Monitor(_) => EXIT_MONITOR,
+            Error::Ipc(IpcError::Remote { code, .. }) => *code,
+            Error::Ipc(IpcError::InUse(_)) | Error::Ipc(IpcError::NotSocket(_)) => EXIT_USAGE,
+            Error::Ipc(_) => EXIT_IO,
+            Error::Config(_) | Error::Exclude(_) | Error::Usage(_) => EXIT_USAGE,
+        }
+    }
 }
@@ -0,0 +1,311 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use log::warn;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use thiserror::Error as TError;
+
+/// Rules applied even without any ignore file.
+const BUILTIN: &[&str] = &[".git/", ".hg/", ".svn/"];
+
+/// How many directories' ignore files are kept in memory at once.
+const CACHE_SIZE: usize = 4096;
+
+#[derive(Debug, TError)]
+pub enum Error {
+    #[error("Ignore file error: {0}")]
+    Ignore(#[from] ignore::Error),
+}
+
+/// The ignore files found in one directory.
+struct Dir {
+    ignore: Option<Gitignore>,
+    gitignore: Option<Gitignore>,
+    repo: bool,
+}
+
+/// The ignore files of recently visited directories. Once `CACHE_SIZE` are held, the
+/// half used longest ago is dropped, to be read again if met later.
+struct Cache {
+    dirs: HashMap<PathBuf, (Arc<Dir>, u64)>,
+    tick: u64,
+}
+
+impl Cache {
+    fn new() -> Cache {
+        Cache {
+            dirs: HashMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn get(&mut self, dir: &Path) -> Option<Arc<Dir>> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.dirs.get_mut(dir).map(|(rules, used)| {
+            *used = tick;
+            Arc::clone(rules)
+        })
+    }
+
+    fn insert(&mut self, dir: PathBuf, rules: Arc<Dir>) {
+        if self.dirs.len() >= CACHE_SIZE {
+            let mut used: Vec<u64> = self.dirs.values().map(|(_, used)| *used).collect();
+            let (_, &mut median, _) = used.select_nth_unstable(CACHE_SIZE / 2);
+            self.dirs.retain(|_, (_, used)| *used > median);
+        }
+        self.tick += 1;
+        self.dirs.insert(dir, (rules, self.tick));
+    }
+
+    fn remove(&mut self, dir: &Path) {
+        self.dirs.remove(dir);
+    }
+}
+
+/// Decides which paths stay out of the index, using gitignore syntax.
+///
+/// Every directory may hold a `.ignore` and a `.gitignore`; rules in deeper directories
+/// win over shallower ones, and in one directory `.ignore` wins over `.gitignore`.
+/// `.gitignore` files only count inside a git repository, up to the directory holding
+/// `.git`. Patterns added with `add_patterns`, the global file and then the built-in
+/// rules for version control directories come last. Ignore files are read once and
+/// cached until `invalidate` is called or the cache makes room for other directories.
+pub struct Excludes {
+    patterns: Vec<Gitignore>,
+    global: Gitignore,
+    builtin: Gitignore,
+    dirs: Mutex<Cache>,
+}
+
+impl Excludes {
+    /// Rules from the ignore files met along each path, plus `global` if it is given and
+    /// exists.
+    pub fn new(global: Option<&Path>) -> Result<Excludes, Error> {
+        let global = match global {
+            Some(p) if p.exists() => {
+                let mut builder = GitignoreBuilder::new("/");
+                if let Some(e) = builder.add(p) {
+                    return Err(Error::Ignore(e));
+                }
+                builder.build()?
+            }
+            _ => Gitignore::empty(),
+        };
+        let mut builtin = GitignoreBuilder::new("/");
+        for line in BUILTIN {
+            builtin.add_line(None, line)?;
+        }
+        Ok(Excludes {
+            patterns: Vec::new(),
+            global,
+            builtin: builtin.build()?,
+            dirs: Mutex::new(Cache::new()),
+        })
+    }
+
+    /// Adds gitignore-style `lines` that apply below `root`, or everywhere if no root is
+    /// given. Sets added later are checked first.
+    pub fn add_patterns<S: AsRef<str>>(&mut self, root: Option<&Path>, lines: &[S]) -> Result<(), Error> {
+        if lines.is_empty() {
+            return Ok(());
+        }
+        let mut builder = GitignoreBuilder::new(root.unwrap_or_else(|| Path::new("/")));
+        for line in lines {
+            builder.add_line(None, line.as_ref())?;
+        }
+        self.patterns.insert(0, builder.build()?);
+        Ok(())
+    }
+
+    /// Whether `path` or any directory above it is excluded.
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        let mut ancestors: Vec<&Path> = path.ancestors().skip(1).collect();
+        ancestors.reverse();
+        ancestors
+            .into_iter()
+            .filter(|a| a.parent().is_some())
+            .any(|a| self.matches(a, true))
+            || self.matches(path, is_dir)
+    }
+
+    /// Whether `path` itself is excluded, for walkers that never enter excluded
+    /// directories and so have checked its parents already.
+    pub fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        let dirs: Vec<Arc<Dir>> = path.ancestors().skip(1).map(|dir| self.dir(dir)).collect();
+        // `.gitignore` files count from `path` up to the nearest directory holding `.git`.
+        let repo = dirs.iter().position(|rules| rules.repo);
+        for (i, rules) in dirs.iter().enumerate() {
+            let in_repo = repo.is_some_and(|repo| i <= repo);
+            for rules in rules.ignore.iter().chain(rules.gitignore.iter().filter(|_| in_repo)) {
+                match rules.matched(path, is_dir) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => {}
+                }
+            }
+        }
+        let patterns = self.patterns.iter().filter(|p| path.starts_with(p.path()));
+        for rules in patterns.chain(vec![&self.global, &self.builtin]) {
+            match rules.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+
+    /// Forgets the cached ignore files of `dir`, to be read again on next use.
+    pub fn invalidate(&self, dir: &Path) {
+        self.dirs.lock().unwrap().remove(dir);
+    }
+
+    /// Whether a change to `path` means its directory's rules must be read again.
+    pub fn is_ignore_file(path: &Path) -> bool {
+        matches!(path.file_name().and_then(|n| n.to_str()), Some(".ignore") | Some(".gitignore"))
+    }
+
+    fn dir(&self, dir: &Path) -> Arc<Dir> {
+        if let Some(rules) = self.dirs.lock().unwrap().get(dir) {
+            return rules;
+        }
+        let rules = Arc::new(Dir {
+            ignore: Excludes::read(dir, ".ignore"),
+            gitignore: Excludes::read(dir, ".gitignore"),
+            repo: dir.join(".git").exists(),
+        });
+        self.dirs.lock().unwrap().insert(dir.to_path_buf(), Arc::clone(&rules));
+        rules
+    }
+
+    fn read(dir: &Path, name: &str) -> Option<Gitignore> {
+        let file = dir.join(name);
+        if !file.is_file() {
+            return None;
+        }
+        let mut builder = GitignoreBuilder::new(dir);
+        if let Some(e) = builder.add(&file) {
+            warn!("{}: {}", file.display(), e);
+        }
+        match builder.build() {
+            Ok(rules) => Some(rules),
+            Err(e) => {
+                warn!("{}: {}", file.display(), e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+
+    fn reset(name: &str) -> PathBuf {
+        let p = Path::new("./instance").join(name);
+        let _ = fs::remove_dir_all(&p);
+        fs::create_dir_all(&p).unwrap();
+        fs::canonicalize(p).unwrap()
+    }
+
+    #[test]
+    fn nested_ignore_files() {
+        let root = reset("exclude_nested");
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::create_dir_all(root.join("web/node_modules/pkg")).unwrap();
+        fs::write(root.join(".gitignore"), "target/\n*.log\n").unwrap();
+        fs::write(root.join("web/.gitignore"), "node_modules/\n!keep.log\n").unwrap();
+        fs::write(root.join("web/.ignore"), "dist/\n").unwrap();
+        let ex = Excludes::new(None).unwrap();
+
+        assert!(ex.is_excluded(&root.join("target"), true));
+        assert!(ex.is_excluded(&root.join("target/debug/quind"), false));
+        assert!(!ex.is_excluded(&root.join("target"), false));
+        assert!(ex.is_excluded(&root.join("web/debug.log"), false));
+        assert!(!ex.is_excluded(&root.join("web/keep.log"), false));
+        assert!(ex.is_excluded(&root.join("web/node_modules/pkg/index.js"), false));
+        assert!(ex.is_excluded(&root.join("web/dist"), true));
+        assert!(ex.is_excluded(&root.join(".git"), true));
+        assert!(!ex.is_excluded(&root.join("web/src/main.rs"), false));
+    }
+
+    #[test]
+    fn gitignore_needs_a_repository() {
+        let root = reset("exclude_repo");
+        fs::create_dir_all(root.join("repo/.git")).unwrap();
+        fs::write(root.join(".gitignore"), "*.rs\n").unwrap();
+        fs::write(root.join(".ignore"), "*.tmp\n").unwrap();
+        let ex = Excludes::new(None).unwrap();
+
+        assert!(!ex.is_excluded(&root.join("repo/main.rs"), false));
+        assert!(ex.is_excluded(&root.join("repo/a.tmp"), false));
+    }
+
+    #[test]
+    fn gitignore_outside_any_repository_is_ignored() {
+        // `./instance` lies inside this crate's own repository.
+        let root = env::temp_dir().join("quind-exclude-norepo");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sub")).unwrap();
+        let root = fs::canonicalize(root).unwrap();
+        fs::write(root.join(".gitignore"), "*.rs\n").unwrap();
+        fs::write(root.join("sub/.gitignore"), "*.c\n").unwrap();
+        fs::write(root.join(".ignore"), "*.tmp\n").unwrap();
+        let ex = Excludes::new(None).unwrap();
+
+        assert!(!ex.is_excluded(&root.join("main.rs"), false));
+        assert!(!ex.is_excluded(&root.join("sub/main.c"), false));
+        assert!(ex.is_excluded(&root.join("sub/a.tmp"), false));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn global_file_and_invalidation() {
+        let root = reset("exclude_global");
+        let global = root.join("global");
+        fs::write(&global, "*.o\n").unwrap();
+        let ex = Excludes::new(Some(&global)).unwrap();
+        assert!(ex.is_excluded(&root.join("x/a.o"), false));
+        assert!(!ex.is_excluded(&root.join("x/a.c"), false));
+
+        fs::write(root.join(".ignore"), "*.c\n").unwrap();
+        assert!(!ex.is_excluded(&root.join("a.c"), false));
+        assert!(Excludes::is_ignore_file(&root.join(".ignore")));
+        ex.invalidate(&root);
+        assert!(ex.is_excluded(&root.join("a.c"), false));
+
+        assert!(Excludes::new(Some(&root)).is_err());
+    }
+
+    #[test]
+    fn added_patterns_apply_below_their_root() {
+        let root = reset("exclude_patterns");
+        let mut ex = Excludes::new(None).unwrap();
+        ex.add_patterns(None, &["*.o"]).unwrap();
+        ex.add_patterns(Some(&root.join("web")), &["dist/", "!keep.o"]).unwrap();
+
+        assert!(ex.is_excluded(&root.join("a.o"), false));
+        assert!(!ex.is_excluded(&root.join("web/keep.o"), false));
+        assert!(ex.is_excluded(&root.join("web/dist/app.js"), false));
+        assert!(!ex.is_excluded(&root.join("dist/app.js"), false));
+        assert!(ex.add_patterns(None, &["[z-a]"]).is_err());
+    }
+    #[test]
+    fn cache_keeps_recent_directories_only() {
+        let root = reset("exclude_cache");
+        fs::write(root.join(".ignore"), "*.tmp\n").unwrap();
+        let ex = Excludes::new(None).unwrap();
+        for i in 0..CACHE_SIZE * 2 {
+            assert!(ex.matches(&root.join(format!("d{}/x.tmp", i)), false));
+        }
+        let cache = ex.dirs.lock().unwrap();
+        assert!(cache.dirs.len() <= CACHE_SIZE);
+        assert!(cache.dirs.contains_key(&root));
+        assert!(!cache.dirs.contains_key(&root.join("d0")));
+    }
+}
@@ -1,18 +1,38 @@
-use crate::monitor::Error as MonitorError;
-use kv::{Config, Store};
-use notify::event::{EventKind, Event, AnyMap};
+use crate::codec::{Encoding, Entry, Error as CodecError};
+use crate::exclude::Excludes;
+use crate::filter::ParseError;
+pub use crate::record::{FileData, FileKind};
+use crate::monitor::{Error as MonitorError, Monitor};
+use crate::search::{trigrams, Order, Query, Sort, Target};
+use kv::{Bucket, Config, Raw, Store};
+use log::{info, warn};
+use notify::event::{EventKind, Event, AnyMap, Flag, ModifyKind, RenameMode};
 use serde::{Deserialize, Serialize};
-use std::convert::From;
+use std::cmp::Ordering;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::convert::TryFrom;
 use std::error;
 use std::fmt;
-use std::path::{Path, PathBuf};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use thiserror::Error as TError;
+use walkdir::WalkDir;
 
 #[derive(Debug, Clone)]
 pub struct RecordError {
     info: String,
 }
 
+impl RecordError {
+    pub fn new<S: Into<String>>(info: S) -> RecordError {
+        RecordError { info: info.into() }
+    }
+}
+
 impl fmt::Display for RecordError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Record error: {}", self.info)
@@ -36,20 +56,56 @@ pub enum Error {
     #[error("Json error: Convert failed: {0}")]
     JSON(#[from] serde_json::Error),
 
-    #[error("Record error: Record CRUD event error: {0}")]
+    #[error("Encoding error: {0}")]
+    Codec(#[from] CodecError),
+
+    #[error("Record CRUD event error: {0}")]
     Record(#[from] RecordError),
+
+    #[error("Monitor error: {0}")]
+    Monitor(#[from] MonitorError),
+
+    #[error("Glob error: {0}")]
+    Glob(#[from] globset::Error),
+
+    #[error("Regex error: {0}")]
+    Regex(#[from] regex::Error),
+
+    #[error("Query error: {0}")]
+    Filter(#[from] ParseError),
+
+    #[error("Cursor error: {0}")]
+    Cursor(String),
+
+    #[error("Collection error: {0}")]
+    Collection(String),
+
+    #[error("Schema error: {0}")]
+    Schema(String),
 }
 
 #[derive(Clone)]
 pub struct Record{
     kind: EventKind,
     paths: Vec<PathBuf>,
+    // Rename halves are already paired by their tracker in `Monitor`.
+    #[allow(dead_code)]
     attrs: AnyMap,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
-pub struct FileData {
-    pub path: String,
+impl TryFrom<Event> for Record {
+    type Error = RecordError;
+
+    fn try_from(e: Event) -> Result<Record, RecordError> {
+        if e.paths.is_empty() {
+            return Err(RecordError::new(format!("{:?} event has no paths", e.kind)));
+        }
+        Ok(Record {
+            kind: e.kind,
+            paths: e.paths,
+            attrs: e.attrs,
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -58,21 +114,667 @@ pub struct File {
     pub data: FileData,
 }
 
+impl File {
+    /// Builds the record for `p`, named after its last path component.
+    pub fn new<P>(p: P) -> Result<File, RecordError>
+    where
+        P: AsRef<Path>,
+    {
+        let p = p.as_ref();
+        let name = p
+            .file_name()
+            .ok_or_else(|| RecordError::new(format!("{} has no file name", p.display())))?;
+        let name = name
+            .to_str()
+            .ok_or_else(|| RecordError::new(format!("{} is not valid UTF-8", p.display())))?;
+        let path = p
+            .to_str()
+            .ok_or_else(|| RecordError::new(format!("{} is not valid UTF-8", p.display())))?;
+        Ok(File {
+            name: String::from(name),
+            data: FileData {
+                path: String::from(path),
+                ..Default::default()
+            },
+        })
+    }
+
+    /// Wraps a stored record, naming it after its path.
+    pub fn from_data(data: FileData) -> Result<File, RecordError> {
+        let name = File::new(&data.path)?.name;
+        Ok(File { name, data })
+    }
+
+    /// Builds the record for `p` carrying the metadata in `m`, which should come from
+    /// `fs::symlink_metadata` so links are described rather than followed.
+    pub fn with_metadata<P>(p: P, m: &fs::Metadata) -> Result<File, RecordError>
+    where
+        P: AsRef<Path>,
+    {
+        let mut f = File::new(p)?;
+        f.data.fill(m);
+        Ok(f)
+    }
+}
+
+/// Outcome of crawling a directory tree into the database.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct IndexStats {
+    pub added: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub removed: usize,
+}
+
+/// One page of search results.
+#[derive(Debug, Default, PartialEq)]
+pub struct Page {
+    pub files: Vec<File>,
+    /// Cursor for the page after this one, if there may be more results.
+    pub next: Option<String>,
+}
+
+/// Scored search results as `Fdb::search_scored` reads them.
+pub type Results<'a> = Box<dyn Iterator<Item = Result<(i64, File), Error>> + 'a>;
+
+/// Stored records as one collection, or several merged, yields them.
+type Records<'a> = Box<dyn Iterator<Item = Result<FileData, Error>> + 'a>;
+
+/// A named set of records kept in a bucket of its own, as `Fdb::collections` lists it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Collection {
+    pub name: String,
+    /// Roots whose records the collection holds; none for the default collection.
+    pub roots: Vec<PathBuf>,
+    pub entries: usize,
+}
+
+/// Which bucket each record belongs in.
+#[derive(Clone)]
+struct Routes {
+    /// Holds records outside every registered root, and all of those written before
+    /// collections existed.
+    default: Bucket<'static, Key, Value>,
+    /// Registered roots, deepest first, with the collection holding their records.
+    roots: Vec<(PathBuf, String)>,
+    collections: BTreeMap<String, Bucket<'static, Key, Value>>,
+}
+
+impl Routes {
+    /// The collection holding `path`'s record, or `None` for the default one.
+    fn collection(&self, path: &str) -> Option<&str> {
+        let path = Path::new(path);
+        self.roots
+            .iter()
+            .find(|(root, _)| path.starts_with(root))
+            .map(|(_, name)| name.as_str())
+    }
+
+    fn bucket(&self, collection: Option<&str>) -> &Bucket<'static, Key, Value> {
+        match collection.and_then(|c| self.collections.get(c)) {
+            Some(bucket) => bucket,
+            None => &self.default,
+        }
+    }
+
+    fn route(&self, path: &str) -> &Bucket<'static, Key, Value> {
+        self.bucket(self.collection(path))
+    }
+
+    /// Every collection with its bucket, the default one first as `None`.
+    fn all(&self) -> impl Iterator<Item = (Option<&str>, &Bucket<'static, Key, Value>)> {
+        let named = self.collections.iter().map(|(name, bucket)| (Some(name.as_str()), bucket));
+        std::iter::once((None, &self.default)).chain(named)
+    }
+
+    fn buckets(&self) -> impl Iterator<Item = &Bucket<'static, Key, Value>> {
+        self.all().map(|(_, bucket)| bucket)
+    }
+
+    fn sort(&mut self) {
+        self.roots.sort_by_key(|(root, _)| Reverse(root.as_os_str().len()));
+    }
+}
+
+/// Writes collected to be committed together with `Fdb::commit`. Later writes to the
+/// same path replace earlier ones; nothing is visible until the commit.
+pub struct Batch {
+    routes: Arc<Routes>,
+    inner: BTreeMap<Option<String>, kv::Batch<Key, Value>>,
+    /// Names other than the basename given to paths in this batch, by collection and
+    /// path, which `remove_from` cannot read back from the store yet.
+    aliases: HashMap<(Option<String>, String), Vec<String>>,
+    len: usize,
+}
+
+impl Batch {
+    /// The writes going to `collection`.
+    fn part(&mut self, collection: Option<&str>) -> &mut kv::Batch<Key, Value> {
+        self.inner.entry(collection.map(String::from)).or_insert_with(kv::Batch::new)
+    }
+
+    /// Stores `f` as the record for its path, replacing what was there. A name other
+    /// than the path's last component is remembered, so `remove` drops its index
+    /// entries too.
+    pub fn add(&mut self, f: &File) -> Result<(), Error> {
+        let routes = Arc::clone(&self.routes);
+        let collection = routes.collection(&f.data.path);
+        let part = self.part(collection);
+        part.set(Key::file(&f.data.path), &Entry::Record(f.data.clone()))?;
+        part.set(Key::name(&f.name, &f.data.path), &Entry::Marker)?;
+        for t in trigrams(&f.name) {
+            part.set(Key::trigram(&t, &f.data.path), &Entry::Marker)?;
+        }
+        if base_name(&f.data.path) != Some(f.name.as_str()) {
+            part.set(Key::alias(&f.data.path, &f.name), &Entry::Marker)?;
+            let key = (collection.map(String::from), f.data.path.clone());
+            self.aliases.entry(key).or_default().push(f.name.clone());
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Replaces the records stored under the name `n` with `d`, kept under that name.
+    pub fn update(&mut self, n: &str, d: FileData) -> Result<(), Error> {
+        let routes = Arc::clone(&self.routes);
+        let prefix = Key::name_prefix(n);
+        for bucket in routes.buckets() {
+            for item in bucket.iter_prefix(prefix.clone()) {
+                let key: Key = item?.key()?;
+                if key.rest(&prefix) != d.path {
+                    self.remove(key.rest(&prefix))?;
+                }
+            }
+        }
+        self.add(&File {
+            name: String::from(n),
+            data: d,
+        })
+    }
+
+    /// Drops the record stored for path `p`, if any.
+    pub fn remove(&mut self, p: &str) -> Result<(), Error> {
+        let routes = Arc::clone(&self.routes);
+        self.remove_from(routes.collection(p), p)
+    }
+
+    /// Drops the record for `p` from `collection`, wherever `p` belongs now.
+    fn remove_from(&mut self, collection: Option<&str>, p: &str) -> Result<(), Error> {
+        let routes = Arc::clone(&self.routes);
+        let mut names = names(routes.bucket(collection), p)?;
+        if let Some(pending) = self.aliases.remove(&(collection.map(String::from), String::from(p))) {
+            names.extend(pending.into_iter().filter(|n| !names.contains(n)).collect::<Vec<_>>());
+        }
+        let part = self.part(collection);
+        part.remove(Key::file(p))?;
+        for name in &names {
+            part.remove(Key::name(name, p))?;
+            for t in trigrams(name) {
+                part.remove(Key::trigram(&t, p))?;
+            }
+            if base_name(p) != Some(name.as_str()) {
+                part.remove(Key::alias(p, name))?;
+            }
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Adds the trigram index entries of `name` for the record at `p` in `collection`.
+    fn index_name(&mut self, collection: Option<&str>, name: &str, p: &str) -> Result<(), Error> {
+        let part = self.part(collection);
+        for t in trigrams(name) {
+            part.set(Key::trigram(&t, p), &Entry::Marker)?;
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Drops the index entry `key` from `collection`.
+    fn unindex(&mut self, collection: Option<&str>, key: Key) -> Result<(), Error> {
+        self.part(collection).remove(key)?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Number of adds, updates and removes collected so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// A file database. The store is opened once and kept for the lifetime of the value;
+/// `Fdb` is `Send` and `Sync`, so one instance can be shared across threads.
 pub struct Fdb {
+    /// What the default collection, holding records outside every registered root,
+    /// is called.
     pub name: String,
     pub path: PathBuf,
     pub config: Config,
+    store: Store,
+    /// Maps each registered root to its collection.
+    registry: Bucket<'static, String, String>,
+    /// Facts about the database itself, such as its schema version.
+    meta: Bucket<'static, String, String>,
+    routes: RwLock<Arc<Routes>>,
+    excludes: Option<Arc<Excludes>>,
+}
+
+/// A key in a collection's bucket. Records, the name index and the name trigram index of
+/// a collection share its bucket so related writes can go in together.
+/// Every key has a one-letter kind and NUL separators, which never occur in file names;
+/// legacy databases keyed by bare names are recognised by their lack of a separator.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Key(String);
+
+impl Key {
+    fn file(path: &str) -> Key {
+        Key(format!("f\0{}", path))
+    }
+
+    /// Prefix of every record key.
+    fn files() -> Key {
+        Key::file("")
+    }
+
+    /// Sorts above every record key.
+    fn files_end() -> Key {
+        Key(String::from("f\u{1}"))
+    }
+
+    fn name_prefix(name: &str) -> Key {
+        Key(format!("n\0{}\0", name))
+    }
+
+    fn name(name: &str, path: &str) -> Key {
+        Key(format!("n\0{}\0{}", name, path))
+    }
+
+    /// Remembers that `path` is indexed under `name` as well as its last component.
+    fn alias(path: &str, name: &str) -> Key {
+        Key(format!("a\0{}\0{}", path, name))
+    }
+
+    fn alias_prefix(path: &str) -> Key {
+        Key(format!("a\0{}\0", path))
+    }
+
+    /// Prefix of every trigram key.
+    fn trigrams() -> Key {
+        Key(String::from("t\0"))
+    }
+
+    fn trigram_prefix(trigram: &str) -> Key {
+        Key(format!("t\0{}\0", trigram))
+    }
+
+    fn trigram(trigram: &str, path: &str) -> Key {
+        Key(format!("t\0{}\0{}", trigram, path))
+    }
+
+    fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// What follows `prefix` in the key, such as the path of an index entry.
+    fn rest(&self, prefix: &Key) -> &str {
+        &self.0[prefix.0.len()..]
+    }
 }
 
+impl AsRef<[u8]> for Key {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+impl<'a> kv::Key<'a> for Key {
+    fn from_raw_key(r: &Raw) -> Result<Key, kv::Error> {
+        Ok(Key(std::str::from_utf8(r)?.to_string()))
+    }
+}
+
+/// What a collection's bucket holds under a key.
+type Value = Entry<FileData>;
+
+/// The last component of `path`, which records are indexed under unless named otherwise.
+fn base_name(path: &str) -> Option<&str> {
+    Path::new(path).file_name().and_then(|n| n.to_str())
+}
+
+/// The names the record for `path` is indexed under in `bucket`: its last component and
+/// any other it was stored with.
+fn names(bucket: &Bucket<Key, Value>, path: &str) -> Result<Vec<String>, Error> {
+    let prefix = Key::alias_prefix(path);
+    let mut names: Vec<String> = base_name(path).map(String::from).into_iter().collect();
+    for item in bucket.iter_prefix(prefix.clone()) {
+        let key: Key = item?.key()?;
+        names.push(String::from(key.rest(&prefix)));
+    }
+    Ok(names)
+}
+
+/// Prefix of the buckets holding named collections, keeping them apart from the store's
+/// own buckets.
+const COLLECTION_PREFIX: &str = "collection:";
+
+/// Bucket mapping each registered root to the name of its collection.
+const REGISTRY: &str = "roots";
+
+/// Bucket holding facts about the database itself.
+const META: &str = "meta";
+
+/// Key in `META` of the schema version the database is laid out in.
+const VERSION_KEY: &str = "version";
+
+/// Key in `META` of the encoding records are written in.
+const ENCODING_KEY: &str = "encoding";
+
+/// Version of the on-disk layout this build writes.
+pub const SCHEMA_VERSION: u32 = 4;
+
+/// A step from one schema version to the next, returning how many records it touched.
+type Migration = fn(&Fdb) -> Result<usize, Error>;
+
+/// Steps from each older layout to the next, the one at index `i` upgrading version
+/// `i + 1`, each with what it does.
+///
+/// 1. One record per name, keyed by the bare name.
+/// 2. Records keyed by path, with a name index; the trigram index may be missing.
+/// 3. The trigram index is complete, and roots may keep their records in collections.
+/// 4. Records may be in bincode; `META` says which encoding they are in.
+const MIGRATIONS: &[(&str, Migration)] = &[
+    ("records keyed by path", Fdb::key_by_path),
+    ("name trigram index built", Fdb::reindex),
+    ("records re-encoded", Fdb::reencode),
+];
+
+fn name_of_bucket(collection: &str) -> String {
+    format!("{}{}", COLLECTION_PREFIX, collection)
+}
+
+/// Most writes the crawler and the event loop collect before committing them.
+const BATCH_SIZE: usize = 1024;
+
 impl Fdb {
-    pub fn new<P>(p: P, n: String) -> Fdb
+    /// Opens the database at `p`, creating it if needed.
+    pub fn new<P>(p: P, n: String) -> Result<Fdb, Error>
     where
         P: AsRef<Path>,
     {
-        Fdb {
+        let config = Config::new(p.as_ref());
+        let opened = Store::new(config.clone()).and_then(|store| {
+            let routes = Routes {
+                default: store.bucket::<Key, Value>(None)?,
+                roots: Vec::new(),
+                collections: BTreeMap::new(),
+            };
+            Ok((store, routes))
+        });
+        let (store, mut routes) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                warn!("{}: {}", p.as_ref().display(), e);
+                return Err(Error::KVInitError);
+            }
+        };
+        let registry = store.bucket::<String, String>(Some(REGISTRY))?;
+        for name in store.buckets() {
+            if let Some(name) = name.strip_prefix(COLLECTION_PREFIX) {
+                routes.collections.insert(String::from(name), store.bucket(Some(&name_of_bucket(name)))?);
+            }
+        }
+        for item in registry.iter() {
+            let item = item?;
+            let name: String = item.value()?;
+            if !routes.collections.contains_key(&name) {
+                routes.collections.insert(name.clone(), store.bucket(Some(&name_of_bucket(&name)))?);
+            }
+            routes.roots.push((PathBuf::from(item.key::<String>()?), name));
+        }
+        routes.sort();
+        let fresh = routes.default.iter().next().is_none() && routes.collections.is_empty();
+        let fdb = Fdb {
             name: n,
             path: p.as_ref().to_path_buf(),
-            config: Config::new(p),
+            config,
+            meta: store.bucket::<String, String>(Some(META))?,
+            store,
+            registry,
+            routes: RwLock::new(Arc::new(routes)),
+            excludes: None,
+        };
+        let version = fdb.version()?;
+        if version > SCHEMA_VERSION {
+            return Err(Error::Schema(format!(
+                "{} has schema version {}, but this build only reads up to {}; open it with a newer quind",
+                fdb.path.display(),
+                version,
+                SCHEMA_VERSION
+            )));
+        }
+        let encoding = fdb.encoding()?;
+        if !encoding.is_supported() {
+            return Err(Error::Schema(format!("{}: {}", fdb.path.display(), CodecError::Unsupported(encoding.name()))));
+        }
+        if fresh && fdb.meta.get(String::from(VERSION_KEY))?.is_none() {
+            fdb.set_version(SCHEMA_VERSION)?;
+            fdb.set_encoding(Encoding::CURRENT)?;
+        }
+        Ok(fdb)
+    }
+
+    /// The schema version the database is laid out in. Databases from before versions
+    /// were recorded are told apart by their keys.
+    pub fn version(&self) -> Result<u32, Error> {
+        if let Some(v) = self.meta.get(String::from(VERSION_KEY))? {
+            return v
+                .parse()
+                .map_err(|_| Error::Schema(format!("{}: unreadable schema version '{}'", self.path.display(), v)));
+        }
+        let routes = self.routes();
+        if routes.default.iter().next().is_none() && routes.collections.is_empty() {
+            return Ok(SCHEMA_VERSION);
+        }
+        for item in routes.default.iter() {
+            if !item?.key::<Key>()?.as_str().contains('\0') {
+                return Ok(1);
+            }
+        }
+        Ok(2)
+    }
+
+    fn set_version(&self, version: u32) -> Result<(), Error> {
+        self.meta.set(String::from(VERSION_KEY), version.to_string())?;
+        self.meta.flush()?;
+        Ok(())
+    }
+
+    /// The encoding records are written in. Databases from before encodings were
+    /// recorded hold JSON.
+    pub fn encoding(&self) -> Result<Encoding, Error> {
+        match self.meta.get(String::from(ENCODING_KEY))? {
+            Some(name) => Encoding::from_name(&name)
+                .ok_or_else(|| Error::Schema(format!("{}: unknown record encoding '{}'", self.path.display(), name))),
+            None => Ok(Encoding::Json),
+        }
+    }
+
+    fn set_encoding(&self, encoding: Encoding) -> Result<(), Error> {
+        self.meta.set(String::from(ENCODING_KEY), String::from(encoding.name()))?;
+        self.meta.flush()?;
+        Ok(())
+    }
+
+    /// Runs the migrations from the stored schema version up to `SCHEMA_VERSION`,
+    /// recording the version after each, so an interrupted upgrade resumes with the
+    /// step that did not finish. Records written by a build with another encoding are
+    /// then re-encoded. Returns how many steps ran.
+    fn upgrade(&self) -> Result<usize, Error> {
+        let version = self.version()?;
+        let steps = &MIGRATIONS[(version.max(1) - 1) as usize..];
+        for (i, (what, step)) in steps.iter().enumerate() {
+            let to = version + i as u32 + 1;
+            let count = step(self)?;
+            self.set_version(to)?;
+            info!("{}: upgraded to schema version {}: {}, {} records", self.path.display(), to, what, count);
+        }
+        if self.encoding()? == Encoding::CURRENT {
+            return Ok(steps.len());
+        }
+        let count = self.reencode()?;
+        info!("{}: re-encoded {} records as {}", self.path.display(), count, Encoding::CURRENT.name());
+        Ok(steps.len() + 1)
+    }
+
+    fn routes(&self) -> Arc<Routes> {
+        Arc::clone(&self.routes.read().unwrap())
+    }
+
+    /// Keeps the records for `root` and below in the collection `name`, a bucket of its
+    /// own, and returns how many stored records moved there. Roots registered below
+    /// `root` keep their own collections; a collection left without roots is dropped.
+    pub fn register<P>(&self, root: P, name: &str) -> Result<usize, Error>
+    where
+        P: AsRef<Path>,
+    {
+        if name.is_empty() || name == self.name {
+            return Err(Error::Collection(format!("the name '{}' is reserved", name)));
+        }
+        let root = root.as_ref();
+        let key = root
+            .to_str()
+            .map(String::from)
+            .ok_or_else(|| RecordError::new(format!("{} is not valid UTF-8", root.display())))?;
+        let root = PathBuf::from(&key);
+        let old = self.routes();
+        let previous = old.roots.iter().find(|(r, _)| r == &root).map(|(_, n)| n.clone());
+        let mut new = (*old).clone();
+        new.roots.retain(|(r, _)| r != &root);
+        new.roots.push((root.clone(), String::from(name)));
+        new.sort();
+        if !new.collections.contains_key(name) {
+            new.collections.insert(String::from(name), self.store.bucket(Some(&name_of_bucket(name)))?);
+        }
+        let mut stray = Vec::new();
+        for (collection, bucket) in old.all().filter(|(c, _)| *c != Some(name)) {
+            for data in Fdb::under_in(bucket, &root)? {
+                if new.collection(&data.path) == Some(name) {
+                    stray.push((collection.map(String::from), data));
+                }
+            }
+        }
+        // Registering a root again finishes a move that a crash cut short.
+        if previous.as_deref() == Some(name) && stray.is_empty() {
+            return Ok(0);
+        }
+        let new = Arc::new(new);
+        *self.routes.write().unwrap() = Arc::clone(&new);
+
+        // Collections are committed one by one, so the moved records are written to
+        // their new one before the root is registered and before the old copies go: a
+        // crash in between leaves duplicates behind, never a record in no collection,
+        // and the next register of the root removes them.
+        let mut adds = self.batch();
+        let mut removes = self.batch();
+        let moved = stray.len();
+        for (collection, data) in stray {
+            removes.remove_from(collection.as_deref(), &data.path)?;
+            adds.add(&File::from_data(data)?)?;
+        }
+        self.commit(adds)?;
+        self.registry.set(key, String::from(name))?;
+        self.commit(removes)?;
+        if let Some(previous) = previous {
+            if !new.roots.iter().any(|(_, n)| n == &previous) {
+                self.drop_collection(&previous)?;
+            }
+        }
+        info!("{}: kept in collection '{}', {} records moved", root.display(), name, moved);
+        Ok(moved)
+    }
+
+    /// Every collection with its roots and size, the default one, called `self.name`,
+    /// first.
+    pub fn collections(&self) -> Result<Vec<Collection>, Error> {
+        let routes = self.routes();
+        let mut collections = Vec::new();
+        for (name, bucket) in routes.all() {
+            let roots = routes
+                .roots
+                .iter()
+                .filter(|(_, n)| Some(n.as_str()) == name)
+                .map(|(root, _)| root.clone());
+            let mut roots: Vec<PathBuf> = roots.collect();
+            roots.sort();
+            collections.push(Collection {
+                name: String::from(name.unwrap_or(&self.name)),
+                roots,
+                entries: bucket.iter_prefix(Key::files()).count(),
+            });
+        }
+        Ok(collections)
+    }
+
+    /// Drops the collection `name` with every record in it, forgets its roots, and
+    /// returns how many records went. The default collection is emptied instead.
+    pub fn drop_collection(&self, name: &str) -> Result<usize, Error> {
+        let old = self.routes();
+        if name == self.name {
+            let entries = old.default.iter_prefix(Key::files()).count();
+            old.default.clear()?;
+            return Ok(entries);
+        }
+        let bucket = match old.collections.get(name) {
+            Some(bucket) => bucket,
+            None => return Err(Error::Collection(format!("there is no collection named '{}'", name))),
+        };
+        let entries = bucket.iter_prefix(Key::files()).count();
+        for (root, _) in old.roots.iter().filter(|(_, n)| n == name) {
+            self.unregister(root)?;
+        }
+        let mut new = (*self.routes()).clone();
+        new.collections.remove(name);
+        *self.routes.write().unwrap() = Arc::new(new);
+        self.store.drop_bucket(name_of_bucket(name))?;
+        Ok(entries)
+    }
+
+    /// Stops keeping `root` in a collection of its own. Records stored for it stay
+    /// where they are, so callers drop them first.
+    fn unregister(&self, root: &Path) -> Result<(), Error> {
+        if let Some(key) = root.to_str() {
+            self.registry.remove(String::from(key))?;
+        }
+        let mut new = (*self.routes()).clone();
+        new.roots.retain(|(r, _)| r != root);
+        *self.routes.write().unwrap() = Arc::new(new);
+        Ok(())
+    }
+
+    /// Leaves paths matched by `excludes` out of crawls and followed events from now on.
+    /// Records already stored for them go at the next `reconcile` or `refresh`.
+    pub fn set_excludes(&mut self, excludes: Arc<Excludes>) {
+        self.excludes = Some(excludes);
+    }
+
+    /// Whether `path` is left out, checking the directories above it too.
+    fn ignores(&self, path: &Path, is_dir: bool) -> bool {
+        match &self.excludes {
+            Some(e) => e.is_excluded(path, is_dir),
+            None => false,
+        }
+    }
+
+    /// Whether `path` itself is left out, for walks that never enter excluded directories.
+    fn ignores_entry(&self, path: &Path, is_dir: bool) -> bool {
+        match &self.excludes {
+            Some(e) => e.matches(path, is_dir),
+            None => false,
         }
     }
 
@@ -83,69 +785,970 @@ impl Fdb {
         }
     }
 
-    pub fn load<P>(p: P, n: String) -> Fdb
+    /// Opens the database at `p` like `new`, first upgrading a database laid out by an
+    /// older version to `SCHEMA_VERSION`.
+    pub fn load<P>(p: P, n: String) -> Result<Fdb, Error>
     where
         P: AsRef<Path>,
     {
-        Fdb {
-            name: n,
-            path: p.as_ref().to_path_buf(),
-            config: Config::new(p),
+        let fdb = Fdb::new(p, n)?;
+        fdb.upgrade()?;
+        Ok(fdb)
+    }
+
+    pub fn check(&self, n: &str) -> Result<bool, Error> {
+        let found = self.routes().buckets().any(|b| b.iter_prefix(Key::name_prefix(n)).next().is_some());
+        Ok(found)
+    }
+
+    pub fn add(&self, f: &File) -> Result<(), Error> {
+        self.transaction(|b| b.add(f))
+    }
+
+    /// Every record named `n`, ordered by path.
+    pub fn get(&self, n: &str) -> Result<Vec<File>, Error> {
+        let prefix = Key::name_prefix(n);
+        let mut files = Vec::new();
+        for bucket in self.routes().buckets() {
+            for item in bucket.iter_prefix(prefix.clone()) {
+                let key: Key = item?.key()?;
+                if let Some(data) = Fdb::lookup(bucket, key.rest(&prefix))? {
+                    files.push(File {
+                        name: String::from(n),
+                        data,
+                    });
+                }
+            }
         }
+        files.sort_by(|a, b| a.data.path.cmp(&b.data.path));
+        Ok(files)
+    }
+
+    /// Replaces the records stored under the name `n` with `d`.
+    pub fn update(&self, n: &str, d: FileData) -> Result<(), Error> {
+        self.transaction(|b| b.update(n, d))
     }
 
-    pub fn check(&self, n: &String) -> Result<bool, Error> {
-        let store = Store::new(self.config.clone()).unwrap();
-        let bucket = store.bucket::<String, String>(None)?;
-        let result = bucket.get(n).unwrap();
-        match result {
-            None => Ok(false),
-            Some(_v) => Ok(true),
+    /// Drops the record stored for path `p`.
+    pub fn remove(&self, p: &str) -> Result<(), Error> {
+        self.transaction(|b| b.remove(p))
+    }
+
+    /// Starts an empty batch of writes for `commit`.
+    pub fn batch(&self) -> Batch {
+        Batch {
+            routes: self.routes(),
+            inner: BTreeMap::new(),
+            aliases: HashMap::new(),
+            len: 0,
         }
     }
 
-    pub fn add(&self, f: &File) -> Result<(), Error> {
-        let store = Store::new(self.config.clone()).unwrap();
-        let bucket = store.bucket::<String, String>(None)?;
-        bucket.set(f.name.clone(), serde_json::to_string(&f.data)?)?;
+    /// Applies the writes in `b` and flushes them to disk, each collection's in one
+    /// atomic step, and returns how many writes were committed. A record and its index
+    /// entries share a collection, so a crash never leaves one half written.
+    ///
+    /// The collections themselves are written one after another, as the store has no
+    /// transaction spanning several: a crash in between keeps the writes to some and
+    /// loses the rest. A batch that moves records across collections, such as a rename
+    /// out of a root kept apart, can then lose the moved records or keep both copies
+    /// until the next `refresh` of the root; `register` orders its writes to only ever
+    /// leave duplicates, which registering the root again removes.
+    pub fn commit(&self, b: Batch) -> Result<usize, Error> {
+        if b.is_empty() {
+            return Ok(0);
+        }
+        for (collection, part) in b.inner {
+            b.routes.bucket(collection.as_deref()).batch(part)?;
+        }
+        b.routes.default.flush()?;
+        Ok(b.len)
+    }
+
+    /// Runs `f` on a fresh batch and commits it if `f` succeeds; on error nothing is
+    /// written. Reads made inside `f` see the database as it was before the commit.
+    pub fn transaction<A, F>(&self, f: F) -> Result<A, Error>
+    where
+        F: FnOnce(&mut Batch) -> Result<A, Error>,
+    {
+        let mut b = self.batch();
+        let a = f(&mut b)?;
+        self.commit(b)?;
+        Ok(a)
+    }
+
+    /// Number of records in the database.
+    pub fn count(&self) -> Result<usize, Error> {
+        Ok(self.routes().buckets().map(|b| b.iter_prefix(Key::files()).count()).sum())
+    }
+
+    /// Every record in the database, ordered by path.
+    pub fn list(&self) -> Result<Vec<File>, Error> {
+        let mut files = Vec::new();
+        for bucket in self.routes().buckets() {
+            files.extend(Fdb::scan(bucket, Some)?);
+        }
+        files.sort_by(|a, b| a.data.path.cmp(&b.data.path));
+        Ok(files)
+    }
+
+    /// Records matching `q`, in the order and number `search_scored` gives.
+    pub fn search<'a>(&'a self, q: &Query) -> Result<impl Iterator<Item = Result<File, Error>> + 'a, Error> {
+        Ok(self.search_scored(q)?.map(|r| r.map(|(_, f)| f)))
+    }
+
+    /// Records matching `q` with their scores, in `q.ordering()`, from `q.after` on,
+    /// skipping `q.offset` and cut to `q.limit`. Every collection is searched unless
+    /// `q.collections` names some.
+    ///
+    /// Results by path are read from the store as they are asked for. Any other order
+    /// needs every match first, of which only the first `q.offset + q.limit` are held.
+    ///
+    /// Name queries with known literals are narrowed through the trigram index;
+    /// everything else, and databases whose index has not been built, is scanned.
+    pub fn search_scored<'a>(&'a self, q: &Query) -> Result<Results<'a>, Error> {
+        let matcher = q.matcher()?;
+        let after = q.resume()?;
+        let (sort, order) = q.ordering();
+        let limit = q.limit.unwrap_or(usize::MAX);
+        let from = match (sort, &after) {
+            (Sort::Path, Some((_, f))) => Some(f.data.path.as_str()),
+            _ => None,
+        };
+        let routes = self.routes();
+        let mut streams = Vec::new();
+        for bucket in self.select(&routes, &q.collections)? {
+            let candidates = match q.target {
+                Target::Name => Fdb::candidates(bucket, &q.literals())?,
+                Target::Path => None,
+            };
+            streams.push(Fdb::records(bucket.clone(), candidates, from, order));
+        }
+        let records: Records = match streams.len() {
+            1 => streams.remove(0),
+            _ => Box::new(Merge::new(streams, order)),
+        };
+        let scored = records.filter_map(move |r| {
+            match r.and_then(|data| Ok(File::from_data(data)?)) {
+                Ok(f) => matcher.score(&f).map(|s| Ok((s, f))),
+                Err(e) => Some(Err(e)),
+            }
+        });
+        if sort == Sort::Path {
+            return Ok(Box::new(scored.skip(q.offset).take(limit)));
+        }
+
+        let keep = limit.saturating_add(q.offset);
+        let mut best = Vec::new();
+        for r in scored {
+            let r = r?;
+            if let Some(a) = &after {
+                if q.compare(&r, a) != Ordering::Greater {
+                    continue;
+                }
+            }
+            best.push(r);
+            if best.len() >= keep.saturating_mul(2).max(BATCH_SIZE) {
+                best.select_nth_unstable_by(keep, |a, b| q.compare(a, b));
+                best.truncate(keep);
+            }
+        }
+        best.sort_by(|a, b| q.compare(a, b));
+        Ok(Box::new(best.into_iter().skip(q.offset).take(limit).map(Ok)))
+    }
+
+    /// The results of `q` collected, with the cursor for the next page when `q.limit`
+    /// cut them short.
+    pub fn page(&self, q: &Query) -> Result<Page, Error> {
+        // One more result than asked for tells whether there is a next page.
+        let probe = match q.limit {
+            Some(n) => q.clone().limit(n.saturating_add(1)),
+            None => q.clone(),
+        };
+        let mut results = self.search_scored(&probe)?.collect::<Result<Vec<_>, _>>()?;
+        let mut next = None;
+        if let Some(n) = q.limit {
+            if results.len() > n {
+                results.truncate(n);
+                next = results.last().map(|(s, f)| q.cursor(*s, f));
+            }
+        }
+        Ok(Page {
+            files: results.into_iter().map(|(_, f)| f).collect(),
+            next,
+        })
+    }
+
+    /// Rebuilds the trigram index from the stored records and returns how many were indexed.
+    ///
+    /// `load` runs this once for databases written before the index existed; searches
+    /// scan collections that have no index. Entries are committed `BATCH_SIZE` at a time,
+    /// those of every record before stale ones are dropped, so a run interrupted over an
+    /// existing index leaves one that still finds every record.
+    pub fn reindex(&self) -> Result<usize, Error> {
+        let routes = self.routes();
+        let mut b = self.batch();
+        let mut count = 0;
+        for (collection, bucket) in routes.all() {
+            for item in bucket.iter_prefix(Key::files()) {
+                let key: Key = item?.key()?;
+                let path = key.rest(&Key::files());
+                for name in names(bucket, path)? {
+                    b.index_name(collection, &name, path)?;
+                }
+                count += 1;
+                if b.len() >= BATCH_SIZE {
+                    self.commit(b)?;
+                    b = self.batch();
+                }
+            }
+            for item in bucket.iter_prefix(Key::trigrams()) {
+                let key: Key = item?.key()?;
+                let stale = match key.rest(&Key::trigrams()).split_once('\0') {
+                    Some((t, path)) => {
+                        !bucket.contains(Key::file(path))? || !names(bucket, path)?.iter().any(|n| trigrams(n).contains(t))
+                    }
+                    None => true,
+                };
+                if stale {
+                    b.unindex(collection, key)?;
+                }
+                if b.len() >= BATCH_SIZE {
+                    self.commit(b)?;
+                    b = self.batch();
+                }
+            }
+        }
+        self.commit(b)?;
+        Ok(count)
+    }
+
+    /// Walks `root` and stores every entry below it, including `root` itself.
+    ///
+    /// Entries already stored with the same data are skipped; unreadable entries and
+    /// paths that cannot be recorded are counted as failed and logged. Changes are
+    /// committed every `BATCH_SIZE` entries, so an interrupted crawl keeps whole batches.
+    pub fn index_tree<P>(&self, root: P) -> Result<IndexStats, Error>
+    where
+        P: AsRef<Path>,
+    {
+        self.crawl(root.as_ref(), |_| {})
+    }
+
+    /// Brings the records under `root` in line with the disk: like `index_tree`, and
+    /// records whose entries are gone are dropped and counted as removed.
+    pub fn reconcile<P>(&self, root: P) -> Result<IndexStats, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let root = root.as_ref();
+        let mut seen = HashSet::new();
+        let mut stats = self.crawl(root, |f| {
+            seen.insert(f.data.path.clone());
+        })?;
+        let mut b = self.batch();
+        for data in self.under(root)? {
+            if !seen.contains(&data.path) {
+                b.remove(&data.path)?;
+            }
+        }
+        stats.removed = self.commit(b)?;
+        Ok(stats)
+    }
+
+    /// Reconciles `root` like `reconcile`, but only lists directories whose stored record
+    /// no longer matches the disk, so the work follows what changed since the last run.
+    ///
+    /// A directory's mtime moves when entries are added, removed or renamed in it, but
+    /// not when a file inside it is rewritten, so the files stored for a directory that
+    /// did not change are still compared with the disk one by one.
+    pub fn refresh<P>(&self, root: P) -> Result<IndexStats, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let mut stats = IndexStats::default();
+        let mut b = self.batch();
+        let mut dirs = vec![root.as_ref().to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            let f = match fs::symlink_metadata(&dir) {
+                Ok(m) => File::with_metadata(&dir, &m)?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    for data in self.under(&dir)? {
+                        b.remove(&data.path)?;
+                        stats.removed += 1;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    warn!("{}: {}", dir.display(), e);
+                    stats.failed += 1;
+                    continue;
+                }
+            };
+            let stored = self.stored(&f.data.path)?;
+            if stored.as_ref() == Some(&f.data) {
+                stats.skipped += 1;
+                if f.data.kind == FileKind::Dir {
+                    for child in self.children(&dir)? {
+                        let is_dir = child.kind == FileKind::Dir;
+                        if self.ignores_entry(Path::new(&child.path), is_dir) {
+                            for data in self.under(Path::new(&child.path))? {
+                                b.remove(&data.path)?;
+                                stats.removed += 1;
+                            }
+                        } else if is_dir {
+                            dirs.push(PathBuf::from(child.path));
+                        } else {
+                            self.recheck(child, &mut b, &mut dirs, &mut stats)?;
+                        }
+                    }
+                }
+                if b.len() >= BATCH_SIZE {
+                    self.commit(b)?;
+                    b = self.batch();
+                }
+                continue;
+            }
+            if let Some(old) = stored {
+                if old.kind == FileKind::Dir && f.data.kind != FileKind::Dir {
+                    for data in self.under(&dir)? {
+                        b.remove(&data.path)?;
+                        stats.removed += 1;
+                    }
+                }
+            }
+            b.add(&f)?;
+            stats.added += 1;
+            if f.data.kind == FileKind::Dir {
+                self.relist(&dir, &mut b, &mut dirs, &mut stats)?;
+            }
+            if b.len() >= BATCH_SIZE {
+                self.commit(b)?;
+                b = self.batch();
+            }
+        }
+        self.commit(b)?;
+        Ok(stats)
+    }
+
+    /// Compares the stored record of a file in a directory that did not change with the
+    /// disk, for files rewritten in place.
+    fn recheck(&self, stored: FileData, b: &mut Batch, dirs: &mut Vec<PathBuf>, stats: &mut IndexStats) -> Result<(), Error> {
+        let f = match fs::symlink_metadata(&stored.path) {
+            Ok(m) => File::with_metadata(&stored.path, &m)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                b.remove(&stored.path)?;
+                stats.removed += 1;
+                return Ok(());
+            }
+            Err(e) => {
+                warn!("{}: {}", stored.path, e);
+                stats.failed += 1;
+                return Ok(());
+            }
+        };
+        if f.data == stored {
+            stats.skipped += 1;
+        } else if f.data.kind == FileKind::Dir {
+            dirs.push(PathBuf::from(stored.path));
+        } else {
+            b.add(&f)?;
+            stats.added += 1;
+        }
         Ok(())
     }
 
-    pub fn get(&self, n: &String) -> Result<File, Error> {
-        let store = Store::new(self.config.clone()).unwrap();
-        let bucket = store.bucket::<String, String>(None)?;
-        let result = bucket.get(n)?;
-        let f = File {
-            name: n.clone(),
-            data: serde_json::from_str(result.unwrap().as_str())?,
+    /// Compares the entries of `dir` with its stored children, queueing subdirectories
+    /// on `dirs` to be checked in turn.
+    fn relist(&self, dir: &Path, b: &mut Batch, dirs: &mut Vec<PathBuf>, stats: &mut IndexStats) -> Result<(), Error> {
+        let mut stored: HashMap<String, FileData> = self
+            .children(dir)?
+            .into_iter()
+            .map(|data| (data.path.clone(), data))
+            .collect();
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("{}: {}", dir.display(), e);
+                stats.failed += 1;
+                return Ok(());
+            }
         };
-        Ok(f)
+        for entry in entries {
+            // `DirEntry::metadata` does not follow symlinks, like `symlink_metadata`.
+            let f = match entry.and_then(|e| Ok((e.metadata()?, e))) {
+                Ok((m, entry)) => File::with_metadata(entry.path(), &m),
+                Err(e) => {
+                    warn!("{}: {}", dir.display(), e);
+                    stats.failed += 1;
+                    continue;
+                }
+            };
+            let f = match f {
+                Ok(f) => f,
+                Err(e) => {
+                    warn!("{}", e);
+                    stats.failed += 1;
+                    continue;
+                }
+            };
+            // Stored records for excluded entries stay in `stored` and are dropped below.
+            if self.ignores_entry(Path::new(&f.data.path), f.data.kind == FileKind::Dir) {
+                continue;
+            }
+            let old = stored.remove(&f.data.path);
+            if f.data.kind == FileKind::Dir {
+                dirs.push(PathBuf::from(&f.data.path));
+                continue;
+            }
+            if let Some(old) = &old {
+                if old.kind == FileKind::Dir {
+                    for data in self.under(Path::new(&old.path))? {
+                        b.remove(&data.path)?;
+                        stats.removed += 1;
+                    }
+                }
+            }
+            if old.as_ref() == Some(&f.data) {
+                stats.skipped += 1;
+            } else {
+                b.add(&f)?;
+                stats.added += 1;
+            }
+        }
+        for path in stored.keys() {
+            for data in self.under(Path::new(path))? {
+                b.remove(&data.path)?;
+                stats.removed += 1;
+            }
+        }
+        Ok(())
     }
 
-    pub fn update(&self, n: &String, d: FileData) -> Result<(), Error> {
-        let store = Store::new(self.config.clone()).unwrap();
-        let bucket = store.bucket::<String, String>(None)?;
-        let f = File {
-            name: n.clone(),
-            data: d,
+    /// Records directly inside `dir`, stepping over everything deeper.
+    fn children(&self, dir: &Path) -> Result<Vec<FileData>, Error> {
+        let mut children = Vec::new();
+        for bucket in self.routes().buckets() {
+            children.extend(Fdb::children_in(bucket, dir)?);
+        }
+        Ok(children)
+    }
+
+    fn children_in(bucket: &Bucket<Key, Value>, dir: &Path) -> Result<Vec<FileData>, Error> {
+        let prefix = match dir.join("").to_str() {
+            Some(dir) => Key::file(dir),
+            None => return Ok(Vec::new()),
+        };
+        let mut children = Vec::new();
+        let mut cursor = prefix.clone();
+        while let Some(item) = bucket.next_key(cursor.clone())? {
+            let key: Key = item.key()?;
+            if !key.as_str().starts_with(prefix.as_str()) {
+                break;
+            }
+            // Keys sort bytewise, so everything below `child/` lies before `child/\u{10ffff}`.
+            let rest = key.rest(&prefix);
+            if let Some(i) = rest.find(MAIN_SEPARATOR) {
+                cursor = Key(format!("{}{}{}\u{10ffff}", prefix.as_str(), &rest[..i], MAIN_SEPARATOR));
+                continue;
+            }
+            cursor = Key(format!("{}{}\u{10ffff}", key.as_str(), MAIN_SEPARATOR));
+            children.push(item.value::<Value>()?.into_record()?);
+        }
+        Ok(children)
+    }
+
+    /// Records for `root` and every entry below it that is not excluded; `root` itself
+    /// is always kept, since it was asked for.
+    fn walk<'a>(&'a self, root: &Path) -> impl Iterator<Item = Result<File, RecordError>> + 'a {
+        WalkDir::new(root)
+            .into_iter()
+            .filter_entry(move |e| e.depth() == 0 || !self.ignores_entry(e.path(), e.file_type().is_dir()))
+            .map(|entry| {
+                // Without `follow_links` the walker reports `symlink_metadata` for each entry.
+                match entry.and_then(|e| Ok((e.metadata()?, e))) {
+                    Ok((m, entry)) => File::with_metadata(entry.path(), &m),
+                    Err(e) => Err(RecordError::new(e.to_string())),
+                }
+            })
+    }
+
+    /// Walks `root` like `index_tree`, handing every entry that was read to `seen`.
+    fn crawl<F>(&self, root: &Path, mut seen: F) -> Result<IndexStats, Error>
+    where
+        F: FnMut(&File),
+    {
+        let mut stats = IndexStats::default();
+        let mut b = self.batch();
+        for f in self.walk(root) {
+            let f = match f {
+                Ok(f) => f,
+                Err(e) => {
+                    warn!("{}", e);
+                    stats.failed += 1;
+                    continue;
+                }
+            };
+            seen(&f);
+            if self.stored(&f.data.path)?.as_ref() == Some(&f.data) {
+                stats.skipped += 1;
+                continue;
+            }
+            b.add(&f)?;
+            stats.added += 1;
+            if b.len() >= BATCH_SIZE {
+                self.commit(b)?;
+                b = self.batch();
+            }
+        }
+        self.commit(b)?;
+        Ok(stats)
+    }
+
+    /// Removes every record located under `root` and returns how many were dropped.
+    /// A registered root stops being one, and a collection holding that root alone is
+    /// dropped at once.
+    pub fn forget<P>(&self, root: P) -> Result<usize, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let root = root.as_ref();
+        let routes = self.routes();
+        let mut dropped = 0;
+        let own = routes.roots.iter().find(|(r, _)| r == root).map(|(_, n)| n.as_str());
+        if let Some(name) = own {
+            match routes.roots.iter().any(|(r, n)| n == name && r != root) {
+                true => self.unregister(root)?,
+                false => dropped = self.drop_collection(name)?,
+            }
+        }
+        let mut b = self.batch();
+        for data in self.under(root)? {
+            b.remove(&data.path)?;
+        }
+        Ok(dropped + self.commit(b)?)
+    }
+
+    /// Every record for `root` or a path below it.
+    fn under(&self, root: &Path) -> Result<Vec<FileData>, Error> {
+        let mut found = Vec::new();
+        for bucket in self.routes().buckets() {
+            found.extend(Fdb::under_in(bucket, root)?);
+        }
+        Ok(found)
+    }
+
+    fn under_in(bucket: &Bucket<Key, Value>, root: &Path) -> Result<Vec<FileData>, Error> {
+        let mut found = Vec::new();
+        let prefix = match root.to_str() {
+            Some(root) => Key::file(root),
+            None => Key::files(),
         };
-        bucket.set(f.name.clone(), serde_json::to_string(&f.data)?)?;
+        for item in bucket.iter_prefix(prefix) {
+            let data = item?.value::<Value>()?.into_record()?;
+            if Path::new(&data.path).starts_with(root) {
+                found.push(data);
+            }
+        }
+        Ok(found)
+    }
+
+    /// Feeds every event reported by `m` into the database until the monitor fails.
+    ///
+    /// Events already queued when one arrives are applied with it, up to `BATCH_SIZE`,
+    /// and committed together. Events that cannot be turned into a `Record` or applied
+    /// are logged and skipped.
+    ///
+    /// Watcher errors and rescan notices mean events may have been lost, so the roots
+    /// they concern are reconciled against the disk once the pending batch is in.
+    pub fn follow(&self, m: &mut Monitor) -> Result<(), Error> {
+        loop {
+            let first = m.get()?;
+            self.follow_from(m, first, Monitor::try_get)?;
+        }
+    }
+
+    /// Like `follow`, but returns once `timeout` has passed, between batches, so the
+    /// caller can do other work and then carry on.
+    pub fn follow_for(&self, m: &mut Monitor, timeout: Duration) -> Result<(), Error> {
+        let until = Instant::now() + timeout;
+        while let Some(first) = m.get_timeout(until.saturating_duration_since(Instant::now()))? {
+            self.follow_from(m, first, Monitor::try_get)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the events `m` has received but still holds back, so that stopping
+    /// loses nothing. The store is flushed once they are in.
+    pub fn finish(&self, m: &mut Monitor) -> Result<(), Error> {
+        let mut held = m.flush().into_iter();
+        if let Some(first) = held.next() {
+            self.follow_from(m, first, |_| held.next())?;
+        }
+        self.routes().default.flush()?;
+        Ok(())
+    }
+
+    fn follow_from<F>(&self, m: &mut Monitor, first: Result<Event, notify::Error>, mut more: F) -> Result<(), Error>
+    where
+        F: FnMut(&mut Monitor) -> Option<Result<Event, notify::Error>>,
+    {
+        let mut b = self.batch();
+        let mut rescan = Vec::new();
+        let mut next = Some(first);
+        while let Some(event) = next {
+            match event {
+                Err(e) => {
+                    rescan.extend(m.roots_of(&e.paths));
+                    warn!("{}", MonitorError::Notify(e));
+                }
+                Ok(e) if matches!(e.flag(), Some(Flag::Rescan)) => {
+                    warn!("watcher asked for a rescan, events may have been lost");
+                    rescan.extend(m.roots_of(&e.paths));
+                }
+                Ok(e) => {
+                    if let Some(excludes) = &self.excludes {
+                        for p in e.paths.iter().filter(|p| Excludes::is_ignore_file(p)) {
+                            if let Some(dir) = p.parent() {
+                                excludes.invalidate(dir);
+                            }
+                        }
+                    }
+                    let applied = Record::try_from(e)
+                        .map_err(Error::Record)
+                        .and_then(|r| self.apply(&mut b, &r));
+                    if let Err(e) = applied {
+                        warn!("{}", e);
+                    }
+                }
+            }
+            next = match b.len() < BATCH_SIZE && rescan.is_empty() {
+                true => more(m),
+                false => None,
+            };
+        }
+        self.commit(b)?;
+        rescan.sort();
+        rescan.dedup();
+        for root in rescan {
+            let stats = self.reconcile(&root)?;
+            info!(
+                "{}: rescanned, {} added, {} removed, {} failed",
+                root.display(),
+                stats.added,
+                stats.removed,
+                stats.failed
+            );
+        }
         Ok(())
     }
 
-    pub fn remove(&self, n: &String) -> Result<(), Error> {
-        let store = Store::new(self.config.clone()).unwrap();
-        let bucket = store.bucket::<String, String>(None)?;
-        bucket.remove(n.clone())?;
+    /// Moves the record for `from`, and every record below it, to `to` and returns how
+    /// many moved. Stored metadata is kept; only `to` itself is looked at again, and is
+    /// indexed if `from` was not known.
+    pub fn rename<P, Q>(&self, from: P, to: Q) -> Result<usize, Error>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        self.transaction(|b| self.rename_into(b, from.as_ref(), to.as_ref()))
+    }
+
+    fn rename_into(&self, b: &mut Batch, from: &Path, to: &Path) -> Result<usize, Error> {
+        let from = File::new(from)?.data.path;
+        let target = File::new(to)?.data.path;
+        let mut moved = self.under(Path::new(&from))?;
+        for data in &mut moved {
+            b.remove(&data.path)?;
+            data.path = format!("{}{}", target, &data.path[from.len()..]);
+        }
+        let count = moved.len();
+        for data in moved {
+            b.add(&File::from_data(data)?)?;
+        }
+        // Renaming changes the entry's ctime, and an unknown source still needs a record.
+        if let Ok(m) = fs::symlink_metadata(to) {
+            b.add(&File::with_metadata(to, &m)?)?;
+        }
+        Ok(count)
+    }
+
+    fn apply(&self, b: &mut Batch, r: &Record) -> Result<(), Error> {
+        if let EventKind::Modify(ModifyKind::Name(RenameMode::Both)) = &r.kind {
+            if let [from, to] = &r.paths[..] {
+                let is_dir = fs::symlink_metadata(to).map(|m| m.is_dir()).unwrap_or(false);
+                match self.ignores(to, is_dir) {
+                    true => {
+                        for data in self.under(from)? {
+                            b.remove(&data.path)?;
+                        }
+                    }
+                    false => {
+                        self.rename_into(b, from, to)?;
+                    }
+                }
+                return Ok(());
+            }
+        }
+        for p in &r.paths {
+            let f = File::new(p)?;
+            match r.kind {
+                // Entries created inside a new directory before it was watched send no
+                // events of their own, so new directories are walked.
+                EventKind::Create(_) => match fs::symlink_metadata(p) {
+                    Ok(m) if self.ignores(p, m.is_dir()) => b.remove(&f.data.path)?,
+                    Ok(m) if m.is_dir() => self.add_tree(b, p)?,
+                    Ok(m) => b.add(&File::with_metadata(p, &m)?)?,
+                    Err(_) => b.remove(&f.data.path)?,
+                },
+                // Rename halves left unpaired cross the edge of the watched tree: a
+                // directory moved in brings entries that send no events of their own,
+                // and one moved out takes everything below it along.
+                EventKind::Modify(ModifyKind::Name(ref mode)) => match fs::symlink_metadata(p) {
+                    Ok(m) if self.ignores(p, m.is_dir()) => b.remove(&f.data.path)?,
+                    Ok(m) if m.is_dir() && *mode != RenameMode::From => self.add_tree(b, p)?,
+                    Ok(m) => b.add(&File::with_metadata(p, &m)?)?,
+                    Err(_) => {
+                        for data in self.under(p)? {
+                            b.remove(&data.path)?;
+                        }
+                    }
+                },
+                // Renames also arrive as modifications and short-lived files may be gone
+                // already, so only keep paths that still exist.
+                EventKind::Modify(_) => match fs::symlink_metadata(p) {
+                    Ok(m) if self.ignores(p, m.is_dir()) => b.remove(&f.data.path)?,
+                    Ok(m) => b.add(&File::with_metadata(p, &m)?)?,
+                    Err(_) => b.remove(&f.data.path)?,
+                },
+                EventKind::Remove(_) => b.remove(&f.data.path)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds `dir` and every entry below it that is not excluded.
+    fn add_tree(&self, b: &mut Batch, dir: &Path) -> Result<(), Error> {
+        for f in self.walk(dir) {
+            match f {
+                Ok(f) => b.add(&f)?,
+                Err(e) => warn!("{}", e),
+            }
+        }
         Ok(())
     }
+
+    /// Rewrites records from the one-record-per-name layout and returns how many moved.
+    fn key_by_path(&self) -> Result<usize, Error> {
+        let mut b = self.batch();
+        let mut moved = 0;
+        for item in self.routes().default.iter() {
+            let item = item?;
+            let key: Key = item.key()?;
+            if key.as_str().contains('\0') {
+                continue;
+            }
+            let f = File {
+                name: String::from(key.as_str()),
+                data: item.value::<Value>()?.into_record()?,
+            };
+            b.add(&f)?;
+            b.part(None).remove(key)?;
+            moved += 1;
+        }
+        self.commit(b)?;
+        Ok(moved)
+    }
+
+    /// Rewrites every record in `Encoding::CURRENT`, unless the database already uses it,
+    /// and records the encoding; returns how many records were rewritten. Each record
+    /// says which encoding it is in, so a rewrite cut short leaves a readable database
+    /// and is finished by the next `load`.
+    fn reencode(&self) -> Result<usize, Error> {
+        if self.encoding()? == Encoding::CURRENT {
+            return Ok(0);
+        }
+        let mut b = self.batch();
+        let mut count = 0;
+        for (collection, bucket) in b.routes.clone().all() {
+            for item in bucket.iter_prefix(Key::files()) {
+                let item = item?;
+                b.part(collection).set(item.key::<Key>()?, &item.value::<Value>()?)?;
+                b.len += 1;
+                count += 1;
+                if b.len() >= BATCH_SIZE {
+                    self.commit(std::mem::replace(&mut b, self.batch()))?;
+                }
+            }
+        }
+        self.commit(b)?;
+        self.set_encoding(Encoding::CURRENT)?;
+        Ok(count)
+    }
+
+    /// Runs every stored record through `keep`, in path order, collecting what it returns.
+    fn scan<T, F>(bucket: &Bucket<Key, Value>, keep: F) -> Result<Vec<T>, Error>
+    where
+        F: Fn(File) -> Option<T>,
+    {
+        let mut kept = Vec::new();
+        for item in bucket.iter_prefix(Key::files()) {
+            let data = item?.value::<Value>()?.into_record()?;
+            kept.extend(keep(File::from_data(data)?));
+        }
+        Ok(kept)
+    }
+
+    /// Paths whose names contain every trigram of `literals`, or `None` when the index
+    /// cannot narrow the search.
+    fn candidates(bucket: &Bucket<Key, Value>, literals: &[String]) -> Result<Option<BTreeSet<String>>, Error> {
+        let indexed = bucket.iter_prefix(Key::trigrams()).next().is_some();
+        if !indexed {
+            return Ok(None);
+        }
+        let mut candidates: Option<BTreeSet<String>> = None;
+        for trigram in literals.iter().flat_map(|l| trigrams(l)) {
+            let prefix = Key::trigram_prefix(&trigram);
+            let mut paths = BTreeSet::new();
+            for item in bucket.iter_prefix(prefix.clone()) {
+                let key: Key = item?.key()?;
+                let path = key.rest(&prefix);
+                let wanted = match &candidates {
+                    Some(c) => c.contains(path),
+                    None => true,
+                };
+                if wanted {
+                    paths.insert(String::from(path));
+                }
+            }
+            if paths.is_empty() {
+                return Ok(Some(paths));
+            }
+            candidates = Some(paths);
+        }
+        Ok(candidates)
+    }
+
+    /// The record stored for `path`, from the collection it belongs to.
+    fn stored(&self, path: &str) -> Result<Option<FileData>, Error> {
+        Fdb::lookup(self.routes().route(path), path)
+    }
+
+    /// The buckets of the collections called `names`, or of all of them if there are
+    /// none; the default collection goes by `self.name`.
+    fn select<'r>(&self, routes: &'r Routes, names: &[String]) -> Result<Vec<&'r Bucket<'static, Key, Value>>, Error> {
+        if names.is_empty() {
+            return Ok(routes.buckets().collect());
+        }
+        names
+            .iter()
+            .map(|name| match routes.collections.get(name) {
+                Some(bucket) => Ok(bucket),
+                None if name == &self.name => Ok(&routes.default),
+                None => Err(Error::Collection(format!("there is no collection named '{}'", name))),
+            })
+            .collect()
+    }
+
+    /// Records stored in `bucket` in path order, or reversed for `Order::Desc`,
+    /// restricted to `candidates` when given and starting past `from`, which is left out.
+    fn records(
+        bucket: Bucket<'static, Key, Value>,
+        candidates: Option<BTreeSet<String>>,
+        from: Option<&str>,
+        order: Order,
+    ) -> Records<'static> {
+        if let Some(mut paths) = candidates {
+            if let Some(from) = from {
+                let later = paths.split_off(from);
+                paths = match order {
+                    Order::Asc => later.into_iter().filter(|p| p != from).collect(),
+                    Order::Desc => paths,
+                };
+            }
+            let found = move |path: String| Fdb::lookup(&bucket, &path).transpose();
+            return match order {
+                Order::Asc => Box::new(paths.into_iter().filter_map(found)),
+                Order::Desc => Box::new(paths.into_iter().rev().filter_map(found)),
+            };
+        }
+        // The smallest key above `f\0<path>` is `f\0<path>\0`, and every record key
+        // sorts below `f\u{1}`.
+        let (low, high) = match (from, order) {
+            (Some(from), Order::Asc) => (Key(format!("{}\0", Key::file(from).as_str())), Key::files_end()),
+            (Some(from), Order::Desc) => (Key::files(), Key::file(from)),
+            (None, _) => (Key::files(), Key::files_end()),
+        };
+        let decode = |item: Result<kv::Item<Key, Value>, kv::Error>| -> Result<FileData, Error> {
+            Ok(item?.value::<Value>()?.into_record()?)
+        };
+        match order {
+            Order::Asc => Box::new(bucket.iter_range(low, high).map(decode)),
+            Order::Desc => Box::new(bucket.iter_range(low, high).rev().map(decode)),
+        }
+    }
+
+    fn lookup(bucket: &Bucket<Key, Value>, path: &str) -> Result<Option<FileData>, Error> {
+        match bucket.get(Key::file(path))? {
+            Some(v) => Ok(Some(v.into_record()?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Streams of records, each in path order, merged into one in the same order.
+struct Merge<'a> {
+    streams: Vec<std::iter::Fuse<Records<'a>>>,
+    heads: Vec<Option<Result<FileData, Error>>>,
+    order: Order,
+}
+
+impl<'a> Merge<'a> {
+    fn new(streams: Vec<Records<'a>>, order: Order) -> Merge<'a> {
+        Merge {
+            heads: streams.iter().map(|_| None).collect(),
+            streams: streams.into_iter().map(Iterator::fuse).collect(),
+            order,
+        }
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = Result<FileData, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (head, stream) in self.heads.iter_mut().zip(&mut self.streams) {
+            if head.is_none() {
+                *head = stream.next();
+            }
+        }
+        let order = self.order;
+        // Errors come out first, as soon as they are read.
+        let first = (0..self.heads.len())
+            .filter(|&i| self.heads[i].is_some())
+            .min_by(|&a, &b| match (&self.heads[a], &self.heads[b]) {
+                (Some(Ok(a)), Some(Ok(b))) => match order {
+                    Order::Asc => a.path.cmp(&b.path),
+                    Order::Desc => b.path.cmp(&a.path),
+                },
+                (Some(Err(_)), _) => Ordering::Less,
+                _ => Ordering::Greater,
+            })?;
+        self.heads[first].take()
+    }
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
-    use std::fs;
+    use lazy_static::lazy_static;
+    use crate::search::{Mode, Order, Sort};
+    use notify::event::{CreateKind, ModifyKind, RemoveKind, RenameMode};
+    use std::sync::Arc;
+    use std::thread;
 
     fn reset(name: &str) -> String {
         let s = format!("./instance/{}", name);
@@ -153,53 +1756,64 @@ mod tests {
         s
     }
 
-    fn set(name: &str) -> String {
-        let s = format!("./instance/{}", name);
-        s
+    lazy_static! {
+        // Tests run in parallel and a store can only be opened once at a time, so the
+        // baseline tests share one instance.
+        static ref DB: Fdb = Fdb::new(reset("db"), String::from("test")).unwrap();
+    }
+
+    /// Opens the store at `path`, which a test has just written to directly. sled lets go
+    /// of its lock file in the background once the last handle is dropped.
+    fn reopen(path: &str, open: fn(String, String) -> Result<Fdb, Error>) -> Result<Fdb, Error> {
+        for _ in 0..100 {
+            match open(String::from(path), String::from("test")) {
+                Err(Error::KVInitError) => thread::sleep(Duration::from_millis(10)),
+                opened => return opened,
+            }
+        }
+        open(String::from(path), String::from("test"))
     }
 
     fn init() -> Fdb {
-        let path = reset("db");
-        let _fdb: Fdb = Fdb::new(path.clone(), String::from("test").clone());
-        _fdb
+        Fdb::new(reset("init"), String::from("test")).unwrap()
     }
 
-    fn load() -> Fdb {
-        let path = set("db");
-        let _fdb: Fdb = Fdb::load(path.clone(), String::from("test").clone());
-        _fdb
+    fn load() -> &'static Fdb {
+        &DB
     }
 
     #[test]
     fn init_success() {
         let _fdb: Fdb = init();
-        let store = Store::new(_fdb.config).unwrap();
-        let bucket = store.bucket::<String, String>(None).unwrap();
+        let routes = _fdb.routes();
+        let bucket = &routes.default;
 
         let file_data = FileData {
             path: String::from("/test"),
+            ..Default::default()
         };
 
         let file: File = File {
             name: String::from("test"),
             data: file_data,
         };
-        bucket.set(file.name.clone(), serde_json::to_string(&file.data).unwrap()).unwrap();
-        assert_eq!(bucket.get(file.name.clone()).unwrap().unwrap(), serde_json::to_string(&file.data).unwrap());
+        bucket.set(Key(file.name.clone()), Entry::Record(file.data.clone())).unwrap();
+        assert_eq!(bucket.get(Key(file.name.clone())).unwrap().unwrap(), Entry::Record(file.data));
     }
 
     #[test]
     fn exist_after_loaded() {
-        let _fdb: Fdb = load();
+        let _fdb: &Fdb = load();
         assert_eq!(_fdb.exists().is_ok(), true);
     }
 
     #[test]
     fn add_one_record() {
-        let _fdb: Fdb = load();
+        let _fdb: &Fdb = load();
 
         let file_data = FileData {
             path: String::from("/test1"),
+            ..Default::default()
         };
 
         let file: File = File {
@@ -207,15 +1821,16 @@ mod tests {
             data: file_data,
         };
         assert_eq!(_fdb.add(&file).is_ok(), true);
-        assert_eq!(_fdb.get(&file.name).unwrap(), file);
+        assert_eq!(_fdb.get(&file.name).unwrap(), vec![file]);
     }
 
     #[test]
     fn get_one_existed_record() {
-        let _fdb: Fdb = load();
+        let _fdb: &Fdb = load();
 
         let file_data = FileData {
             path: String::from("/test2"),
+            ..Default::default()
         };
 
         let file: File = File {
@@ -223,15 +1838,16 @@ mod tests {
             data: file_data,
         };
         let _res = _fdb.add(&file);
-        assert_eq!(_fdb.get(&file.name).unwrap(), file);
+        assert_eq!(_fdb.get(&file.name).unwrap(), vec![file]);
     }
 
     #[test]
     fn check_key_existed() {
-        let _fdb: Fdb = load();
+        let _fdb: &Fdb = load();
 
         let file_data = FileData {
             path: String::from("/test3"),
+            ..Default::default()
         };
 
         let file: File = File {
@@ -245,10 +1861,11 @@ mod tests {
 
     #[test]
     fn update_one_existed_record() {
-        let _fdb: Fdb = load();
+        let _fdb: &Fdb = load();
 
         let file_data = FileData {
             path: String::from("/test4"),
+            ..Default::default()
         };
 
         let file: File = File {
@@ -258,6 +1875,7 @@ mod tests {
 
         let new_file_data = FileData {
             path: String::from("/another"),
+            ..Default::default()
         };
 
         let new_file = File {
@@ -266,15 +1884,16 @@ mod tests {
         } ;
         let _res = _fdb.add(&file);
         let _res = _fdb.update(&file.name, new_file_data);
-        assert_eq!(_fdb.get(&file.name).unwrap(), new_file);
+        assert_eq!(_fdb.get(&file.name).unwrap(), vec![new_file]);
     }
 
     #[test]
     fn remove_one_existed_record() {
-        let _fdb: Fdb = load();
+        let _fdb: &Fdb = load();
 
         let file_data = FileData {
             path: String::from("/test5"),
+            ..Default::default()
         };
 
         let file: File = File {
@@ -282,7 +1901,666 @@ mod tests {
             data: file_data,
         };
         let _res = _fdb.add(&file);
-        let _res = _fdb.remove(&file.name);
+        let _res = _fdb.remove(&file.data.path);
         assert_eq!(_fdb.check(&file.name).unwrap(), false);
     }
+
+    #[test]
+    fn record_needs_a_path() {
+        let event = Event::new(EventKind::Create(CreateKind::File));
+        assert!(Record::try_from(event).is_err());
+    }
+
+    #[test]
+    fn apply_follows_file_events() {
+        let _fdb = Fdb::new(reset("record"), String::from("test")).unwrap();
+        let dir = reset("record_tree");
+        fs::create_dir_all(&dir).unwrap();
+        let path = Path::new(&dir).join("a.txt");
+        let key = path.to_str().unwrap();
+        let apply = |e: Event| {
+            let mut b = _fdb.batch();
+            _fdb.apply(&mut b, &Record::try_from(e).unwrap()).unwrap();
+            _fdb.commit(b).unwrap();
+        };
+
+        fs::write(&path, "a").unwrap();
+        apply(Event::new(EventKind::Create(CreateKind::File)).add_path(path.clone()));
+        assert_eq!(_fdb.stored(key).unwrap().unwrap().path, key);
+
+        apply(Event::new(EventKind::Remove(RemoveKind::File)).add_path(PathBuf::from("/elsewhere/a.txt")));
+        assert!(_fdb.stored(key).unwrap().is_some());
+
+        fs::remove_file(&path).unwrap();
+        apply(Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::From))).add_path(path.clone()));
+        assert!(_fdb.stored(key).unwrap().is_none());
+        assert!(_fdb.routes().default.iter_prefix(Key::name_prefix("a.txt")).next().is_none());
+    }
+
+    #[test]
+    fn index_tree_counts_entries() {
+        let _fdb = Fdb::new(reset("crawl"), String::from("test")).unwrap();
+        let root = reset("crawl_tree");
+        fs::create_dir_all(Path::new(&root).join("sub")).unwrap();
+        fs::write(Path::new(&root).join("one"), "1").unwrap();
+        fs::write(Path::new(&root).join("sub/two"), "2").unwrap();
+
+        let first = _fdb.index_tree(&root).unwrap();
+        assert_eq!(first, IndexStats { added: 4, skipped: 0, failed: 0, removed: 0 });
+        let second = _fdb.index_tree(&root).unwrap();
+        assert_eq!(second, IndexStats { added: 0, skipped: 4, failed: 0, removed: 0 });
+    }
+
+    #[test]
+    fn same_name_in_two_directories() {
+        let _fdb = Fdb::new(reset("names"), String::from("test")).unwrap();
+        let first = File::new("/a/Cargo.toml").unwrap();
+        let second = File::new("/b/Cargo.toml").unwrap();
+        _fdb.add(&first).unwrap();
+        _fdb.add(&second).unwrap();
+        assert_eq!(_fdb.get("Cargo.toml").unwrap(), vec![first, second]);
+
+        _fdb.remove("/a/Cargo.toml").unwrap();
+        assert_eq!(_fdb.get("Cargo.toml").unwrap(), vec![File::new("/b/Cargo.toml").unwrap()]);
+    }
+
+    #[test]
+    fn other_names_go_with_their_record() {
+        let _fdb = Fdb::new(reset("aliases"), String::from("test")).unwrap();
+        let data = File::new("/a/real.txt").unwrap().data;
+        _fdb.update("nickname", data.clone()).unwrap();
+        assert!(_fdb.check("nickname").unwrap());
+        let routes = _fdb.routes();
+        assert!(Fdb::candidates(&routes.default, &[String::from("nick")]).unwrap().unwrap().contains("/a/real.txt"));
+
+        _fdb.remove("/a/real.txt").unwrap();
+        assert!(!_fdb.check("nickname").unwrap());
+        assert!(Fdb::candidates(&routes.default, &[String::from("nick")]).unwrap().is_none());
+        assert!(routes.default.iter().next().is_none());
+
+        // Also when the name was given earlier in the same batch.
+        _fdb.transaction(|b| {
+            b.add(&File { name: String::from("nickname"), data: data.clone() })?;
+            b.remove("/a/real.txt")
+        })
+        .unwrap();
+        assert!(routes.default.iter().next().is_none());
+    }
+
+    #[test]
+    fn load_migrates_name_keyed_records() {
+        let path = reset("legacy");
+        {
+            let store = Store::new(Config::new(&path)).unwrap();
+            let bucket = store.bucket::<String, String>(None).unwrap();
+            bucket.set(String::from("legacy"), String::from(r#"{"path":"/old/legacy"}"#)).unwrap();
+        }
+        let _fdb = reopen(&path, Fdb::new).unwrap();
+        assert_eq!(_fdb.version().unwrap(), 1);
+        _fdb.upgrade().unwrap();
+        assert_eq!(_fdb.get("legacy").unwrap(), vec![File::new("/old/legacy").unwrap()]);
+        assert_eq!(_fdb.count().unwrap(), 1);
+        assert_eq!(_fdb.version().unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn schema_versions_are_recorded_and_checked() {
+        assert_eq!(MIGRATIONS.len() as u32, SCHEMA_VERSION - 1);
+        let fresh = reset("schema_fresh");
+        assert_eq!(Fdb::new(&fresh, String::from("test")).unwrap().version().unwrap(), SCHEMA_VERSION);
+
+        // Path-keyed records from before versions were recorded get their trigram index.
+        let path = reset("schema_unversioned");
+        {
+            let store = Store::new(Config::new(&path)).unwrap();
+            let bucket = store.bucket::<String, String>(None).unwrap();
+            bucket.set(String::from("f\0/old/monitor.rs"), String::from(r#"{"path":"/old/monitor.rs"}"#)).unwrap();
+        }
+        let _fdb = reopen(&path, Fdb::load).unwrap();
+        assert_eq!(_fdb.version().unwrap(), SCHEMA_VERSION);
+        let routes = _fdb.routes();
+        assert!(Fdb::candidates(&routes.default, &[String::from("onit")]).unwrap().is_some());
+        drop(routes);
+        drop(_fdb);
+
+        let newer = reset("schema_newer");
+        {
+            let store = Store::new(Config::new(&newer)).unwrap();
+            let meta = store.bucket::<String, String>(Some(META)).unwrap();
+            meta.set(String::from(VERSION_KEY), (SCHEMA_VERSION + 1).to_string()).unwrap();
+        }
+        match reopen(&newer, Fdb::load) {
+            Err(Error::Schema(message)) => assert!(message.contains("newer quind")),
+            _ => panic!("expected a schema error"),
+        }
+    }
+
+    #[test]
+    fn json_records_are_reencoded_on_load() {
+        let path = reset("encoding_json");
+        {
+            let store = Store::new(Config::new(&path)).unwrap();
+            let bucket = store.bucket::<String, String>(None).unwrap();
+            bucket.set(String::from("f\0/old/monitor.rs"), String::from(r#"{"path":"/old/monitor.rs"}"#)).unwrap();
+            bucket.set(String::from("n\0monitor.rs\0/old/monitor.rs"), String::new()).unwrap();
+            let meta = store.bucket::<String, String>(Some(META)).unwrap();
+            meta.set(String::from(VERSION_KEY), String::from("3")).unwrap();
+        }
+        let _fdb = reopen(&path, Fdb::new).unwrap();
+        assert_eq!(_fdb.encoding().unwrap(), Encoding::Json);
+        _fdb.upgrade().unwrap();
+        assert_eq!(_fdb.version().unwrap(), SCHEMA_VERSION);
+        assert_eq!(_fdb.encoding().unwrap(), Encoding::CURRENT);
+        assert_eq!(_fdb.get("monitor.rs").unwrap(), vec![File::new("/old/monitor.rs").unwrap()]);
+        let raw = _fdb.store.bucket::<Key, Raw>(None).unwrap().get(Key::file("/old/monitor.rs")).unwrap().unwrap();
+        assert_eq!(Encoding::of(&raw).unwrap(), Encoding::CURRENT);
+    }
+
+    #[test]
+    fn unknown_encodings_are_refused() {
+        let path = reset("encoding_unknown");
+        {
+            let store = Store::new(Config::new(&path)).unwrap();
+            let meta = store.bucket::<String, String>(Some(META)).unwrap();
+            meta.set(String::from(ENCODING_KEY), String::from("morse")).unwrap();
+        }
+        assert!(matches!(reopen(&path, Fdb::load), Err(Error::Schema(_))));
+
+        if !Encoding::Bincode.is_supported() {
+            let path = reset("encoding_unsupported");
+            {
+                let store = Store::new(Config::new(&path)).unwrap();
+                let meta = store.bucket::<String, String>(Some(META)).unwrap();
+                meta.set(String::from(ENCODING_KEY), String::from("bincode")).unwrap();
+            }
+            match reopen(&path, Fdb::load) {
+                Err(Error::Schema(message)) => assert!(message.contains("`binary` feature")),
+                _ => panic!("expected a schema error"),
+            }
+        }
+    }
+
+    #[test]
+    fn path_only_records_still_parse() {
+        let data: FileData = serde_json::from_str(r#"{"path":"/old"}"#).unwrap();
+        assert_eq!(data.path, "/old");
+        assert_eq!(data.kind, FileKind::Other);
+        assert_eq!(data.size, 0);
+    }
+
+    #[test]
+    fn metadata_describes_entry() {
+        let dir = reset("stat");
+        fs::create_dir_all(&dir).unwrap();
+        let path = Path::new(&dir).join("five");
+        fs::write(&path, "12345").unwrap();
+
+        let f = File::with_metadata(&path, &fs::symlink_metadata(&path).unwrap()).unwrap();
+        assert_eq!(f.data.kind, FileKind::File);
+        assert_eq!(f.data.size, 5);
+        assert!(f.data.mtime > 0);
+        let d = File::with_metadata(&dir, &fs::symlink_metadata(&dir).unwrap()).unwrap();
+        assert_eq!(d.data.kind, FileKind::Dir);
+
+        #[cfg(unix)]
+        {
+            let link = Path::new(&dir).join("link");
+            std::os::unix::fs::symlink(&path, &link).unwrap();
+            let l = File::with_metadata(&link, &fs::symlink_metadata(&link).unwrap()).unwrap();
+            assert_eq!(l.data.kind, FileKind::Symlink);
+            assert_ne!(l.data.ino, f.data.ino);
+        }
+    }
+
+    #[test]
+    fn search_scans_records() {
+        let _fdb = Fdb::new(reset("search"), String::from("test")).unwrap();
+        let root = reset("search_tree");
+        fs::create_dir_all(Path::new(&root).join("src")).unwrap();
+        fs::write(Path::new(&root).join("src/main.rs"), "").unwrap();
+        fs::write(Path::new(&root).join("Main.md"), "").unwrap();
+        _fdb.index_tree(&root).unwrap();
+
+        let names = |q: Query| -> Vec<String> { _fdb.search(&q).unwrap().map(|f| f.unwrap().name).collect() };
+        assert_eq!(names(Query::new("main")), vec!["main.rs"]);
+        assert_eq!(names(Query::new("main").ignore_case(true)), vec!["Main.md", "main.rs"]);
+        let found: Vec<File> = _fdb.search(&Query::new("main.rs")).unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(found[0].data.kind, FileKind::File);
+    }
+
+    #[test]
+    fn trigram_index_narrows_and_rebuilds() {
+        let _fdb = Fdb::new(reset("trigram"), String::from("test")).unwrap();
+        let root = reset("trigram_tree");
+        fs::create_dir_all(Path::new(&root).join("src")).unwrap();
+        for name in &["src/monitor.rs", "src/fdb.rs", "MONITOR.md", "mon"] {
+            fs::write(Path::new(&root).join(name), "").unwrap();
+        }
+        _fdb.index_tree(&root).unwrap();
+
+        let names = |q: Query| -> Vec<String> { _fdb.search(&q).unwrap().map(|f| f.unwrap().name).collect() };
+        let queries = || {
+            vec![
+                Query::new("onit"),
+                Query::new("onit").ignore_case(true),
+                Query::new("*.rs").mode(Mode::Glob),
+                Query::new(r"^mon.*\.rs$").mode(Mode::Regex),
+                Query::new("mo"),
+            ]
+        };
+        let indexed: Vec<Vec<String>> = queries().into_iter().map(names).collect();
+        assert_eq!(indexed[0], vec!["monitor.rs"]);
+        assert_eq!(indexed[1], vec!["MONITOR.md", "monitor.rs"]);
+        assert_eq!(indexed[2], vec!["fdb.rs", "monitor.rs"]);
+        assert_eq!(indexed[3], vec!["monitor.rs"]);
+        assert_eq!(indexed[4], vec!["mon", "monitor.rs"]);
+
+        let routes = _fdb.routes();
+        let bucket = &routes.default;
+        let candidates = Fdb::candidates(bucket, &Query::new("onit").literals()).unwrap().unwrap();
+        assert_eq!(candidates.len(), 2);
+        for item in bucket.iter_prefix(Key::trigrams()) {
+            bucket.remove(item.unwrap().key::<Key>().unwrap()).unwrap();
+        }
+        assert!(Fdb::candidates(bucket, &Query::new("onit").literals()).unwrap().is_none());
+
+        let entries = |bucket: &Bucket<Key, Value>| -> Vec<Key> {
+            bucket.iter_prefix(Key::trigrams()).map(|item| item.unwrap().key().unwrap()).collect()
+        };
+        let scanned: Vec<Vec<String>> = queries().into_iter().map(names).collect();
+        assert_eq!(scanned, indexed);
+        assert_eq!(_fdb.reindex().unwrap(), 6);
+        let rebuilt: Vec<Vec<String>> = queries().into_iter().map(names).collect();
+        assert_eq!(rebuilt, indexed);
+        let built = entries(bucket);
+
+        // Entries for records that are gone, or for names they no longer have, are dropped.
+        let monitor = Path::new(&root).join("src/monitor.rs").to_str().unwrap().to_string();
+        bucket.set(Key::trigram("zzz", "/gone"), Entry::Marker).unwrap();
+        bucket.set(Key::trigram("zzz", &monitor), Entry::Marker).unwrap();
+        assert_eq!(_fdb.reindex().unwrap(), 6);
+        assert_eq!(entries(bucket), built);
+    }
+
+    #[test]
+    fn fuzzy_results_are_ranked_and_limited() {
+        let _fdb = Fdb::new(reset("fuzzy"), String::from("test")).unwrap();
+        let root = reset("fuzzy_tree");
+        for dir in &["src", "docs", "m/o"] {
+            fs::create_dir_all(Path::new(&root).join(dir)).unwrap();
+        }
+        for name in &["src/monitor.rs", "docs/mon.md", "m/o/n.rs"] {
+            fs::write(Path::new(&root).join(name), "").unwrap();
+        }
+        _fdb.index_tree(&root).unwrap();
+
+        let q = Query::new("mon").mode(Mode::Fuzzy);
+        let ranked: Vec<(i64, File)> = _fdb.search_scored(&q).unwrap().collect::<Result<_, _>>().unwrap();
+        let names: Vec<&str> = ranked.iter().map(|(_, f)| f.name.as_str()).collect();
+        assert_eq!(&names[..3], &["mon.md", "monitor.rs", "n.rs"]);
+        assert!(ranked.windows(2).all(|w| w[0].0 >= w[1].0));
+
+        let top: Vec<File> = _fdb.search(&q.limit(2)).unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].name, "mon.md");
+    }
+
+    #[test]
+    fn results_are_sorted_and_paged() {
+        let _fdb = Fdb::new(reset("paged"), String::from("test")).unwrap();
+        _fdb.transaction(|b| {
+            for (i, name) in ["c.log", "a.log", "e.txt", "b.log", "d.log"].iter().enumerate() {
+                let data = FileData {
+                    path: format!("/p/{}/{}", i, name),
+                    size: [30, 10, 0, 10, 20][i],
+                    mtime: i as i64,
+                    ..Default::default()
+                };
+                b.add(&File::from_data(data)?)?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        let names = |q: &Query| -> Vec<String> { _fdb.search(q).unwrap().map(|f| f.unwrap().name).collect() };
+
+        let q = Query::new(".log");
+        assert_eq!(names(&q), vec!["c.log", "a.log", "b.log", "d.log"]);
+        assert_eq!(names(&q.clone().order(Order::Desc).offset(1).limit(2)), vec!["b.log", "a.log"]);
+        assert_eq!(names(&q.clone().sort(Sort::Name)), vec!["a.log", "b.log", "c.log", "d.log"]);
+        assert_eq!(names(&q.clone().sort(Sort::Size)), vec!["a.log", "b.log", "d.log", "c.log"]);
+        assert_eq!(names(&q.clone().sort(Sort::Mtime).order(Order::Desc).limit(1)), vec!["d.log"]);
+
+        // Paging with cursors visits every result once, even as records come and go.
+        for q in [q.clone().sort(Sort::Size).order(Order::Desc), q.clone()] {
+            let mut seen = Vec::new();
+            let mut page = _fdb.page(&q.clone().limit(3)).unwrap();
+            seen.extend(page.files.iter().map(|f| f.name.clone()));
+            let early = FileData {
+                path: String::from("/p/0/0.log"),
+                size: 99,
+                ..Default::default()
+            };
+            _fdb.transaction(|b| b.add(&File::from_data(early)?)).unwrap();
+            while let Some(cursor) = page.next.take() {
+                page = _fdb.page(&q.clone().limit(3).after(cursor)).unwrap();
+                seen.extend(page.files.iter().map(|f| f.name.clone()));
+            }
+            _fdb.transaction(|b| b.remove("/p/0/0.log")).unwrap();
+            assert_eq!(seen, names(&q));
+        }
+
+        let other = _fdb.page(&q.clone().limit(1)).unwrap().next.unwrap();
+        assert!(matches!(_fdb.search(&q.clone().sort(Sort::Name).after(other)), Err(Error::Cursor(_))));
+        assert!(matches!(_fdb.search(&q.clone().after("zz")), Err(Error::Cursor(_))));
+    }
+
+    #[test]
+    fn collections_keep_roots_apart() {
+        let path = reset("collections");
+        let tree = reset("collections_tree");
+        for (dir, name) in &[("a", "one.rs"), ("a", "two.rs"), ("b", "three.rs")] {
+            fs::create_dir_all(Path::new(&tree).join(dir)).unwrap();
+            fs::write(Path::new(&tree).join(dir).join(name), "").unwrap();
+        }
+        let tree = fs::canonicalize(&tree).unwrap();
+        let (a, b) = (tree.join("a"), tree.join("b"));
+        let _fdb = Fdb::new(&path, String::from("test")).unwrap();
+        _fdb.index_tree(&a).unwrap();
+        assert_eq!(_fdb.register(&a, "a").unwrap(), 3);
+        _fdb.register(&b, &b.display().to_string()).unwrap();
+        _fdb.index_tree(&b).unwrap();
+        assert!(matches!(_fdb.register(&b, "test"), Err(Error::Collection(_))));
+
+        let names = |fdb: &Fdb, q: Query| -> Vec<String> { fdb.search(&q).unwrap().map(|f| f.unwrap().name).collect() };
+        assert_eq!(names(&_fdb, Query::new(".rs")), vec!["one.rs", "two.rs", "three.rs"]);
+        assert_eq!(names(&_fdb, Query::new(".rs").order(Order::Desc)), vec!["three.rs", "two.rs", "one.rs"]);
+        assert_eq!(names(&_fdb, Query::new(".rs").collection("a")), vec!["one.rs", "two.rs"]);
+        assert!(names(&_fdb, Query::new(".rs").collection("test")).is_empty());
+        assert!(matches!(_fdb.search(&Query::new(".rs").collection("c")), Err(Error::Collection(_))));
+        assert_eq!(_fdb.get("three.rs").unwrap().len(), 1);
+        assert_eq!(_fdb.count().unwrap(), 5);
+
+        drop(_fdb);
+        let _fdb = Fdb::new(&path, String::from("test")).unwrap();
+        let listed: Vec<(String, usize)> = _fdb.collections().unwrap().into_iter().map(|c| (c.name, c.entries)).collect();
+        assert_eq!(listed[0], (String::from("test"), 0));
+        assert!(listed.contains(&(String::from("a"), 3)));
+        assert!(listed.contains(&(b.display().to_string(), 2)));
+
+        // Clearing one root leaves the others alone.
+        assert_eq!(_fdb.forget(&b).unwrap(), 2);
+        assert_eq!(_fdb.collections().unwrap().len(), 2);
+        _fdb.index_tree(&b).unwrap();
+        assert_eq!(_fdb.drop_collection("a").unwrap(), 3);
+        assert_eq!(names(&_fdb, Query::new(".rs")), vec!["three.rs"]);
+        assert_eq!(_fdb.collections().unwrap().len(), 1);
+        assert!(matches!(_fdb.drop_collection("a"), Err(Error::Collection(_))));
+    }
+
+    #[test]
+    fn registering_again_finishes_an_interrupted_move() {
+        let path = reset("interrupted");
+        let tree = reset("interrupted_tree");
+        fs::create_dir_all(&tree).unwrap();
+        fs::write(Path::new(&tree).join("moved.rs"), "").unwrap();
+        let tree = fs::canonicalize(&tree).unwrap();
+        let _fdb = Fdb::new(&path, String::from("test")).unwrap();
+        _fdb.index_tree(&tree).unwrap();
+        // As if a crash came after the root was registered, before the records moved.
+        _fdb.registry.set(tree.display().to_string(), String::from("a")).unwrap();
+        drop(_fdb);
+
+        let _fdb = Fdb::new(&path, String::from("test")).unwrap();
+        assert_eq!(_fdb.register(&tree, "a").unwrap(), 2);
+        let listed: Vec<(String, usize)> = _fdb.collections().unwrap().into_iter().map(|c| (c.name, c.entries)).collect();
+        assert_eq!(listed, vec![(String::from("test"), 0), (String::from("a"), 2)]);
+        assert_eq!(_fdb.get("moved.rs").unwrap().len(), 1);
+        assert_eq!(_fdb.register(&tree, "a").unwrap(), 0);
+    }
+
+    #[test]
+    fn the_filesystem_root_can_be_a_collection() {
+        let _fdb = Fdb::new(reset("filesystem_root"), String::from("test")).unwrap();
+        _fdb.add(&File::new("/x/one").unwrap()).unwrap();
+        _fdb.add(&File::new("/y/two").unwrap()).unwrap();
+        assert_eq!(_fdb.register("/", "all").unwrap(), 2);
+        let listed = _fdb.collections().unwrap();
+        assert_eq!(listed[0].entries, 0);
+        assert_eq!((listed[1].name.as_str(), listed[1].entries), ("all", 2));
+        assert_eq!(listed[1].roots, vec![PathBuf::from("/")]);
+        assert_eq!(_fdb.get("two").unwrap(), vec![File::new("/y/two").unwrap()]);
+    }
+
+    #[test]
+    fn store_is_opened_once_and_shared() {
+        let path = reset("shared");
+        let fdb = Arc::new(Fdb::new(&path, String::from("test")).unwrap());
+        assert!(matches!(Fdb::new(&path, String::from("test")), Err(Error::KVInitError)));
+
+        let writers: Vec<_> = (0..4)
+            .map(|i| {
+                let fdb = Arc::clone(&fdb);
+                thread::spawn(move || {
+                    for j in 0..25 {
+                        fdb.add(&File::new(format!("/t{}/f{}", i, j)).unwrap()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for w in writers {
+            w.join().unwrap();
+        }
+        assert_eq!(fdb.count().unwrap(), 100);
+    }
+
+    #[test]
+    fn open_failure_is_an_error() {
+        let path = reset("not_a_dir");
+        fs::create_dir_all("./instance").unwrap();
+        fs::write(&path, "").unwrap();
+        assert!(matches!(Fdb::new(&path, String::from("test")), Err(Error::KVInitError)));
+    }
+
+    #[test]
+    fn batches_commit_all_or_nothing() {
+        let _fdb = Fdb::new(reset("batch"), String::from("test")).unwrap();
+        let mut b = _fdb.batch();
+        b.add(&File::new("/x/one").unwrap()).unwrap();
+        b.add(&File::new("/x/two").unwrap()).unwrap();
+        b.remove("/x/one").unwrap();
+        assert_eq!(b.len(), 3);
+        assert_eq!(_fdb.count().unwrap(), 0);
+        assert_eq!(_fdb.commit(b).unwrap(), 3);
+        assert_eq!(_fdb.list().unwrap(), vec![File::new("/x/two").unwrap()]);
+
+        let failed: Result<(), Error> = _fdb.transaction(|b| {
+            b.remove("/x/two")?;
+            b.add(&File::new("/x/three").unwrap())?;
+            Err(Error::Record(RecordError::new("stop")))
+        });
+        assert!(failed.is_err());
+        assert_eq!(_fdb.list().unwrap(), vec![File::new("/x/two").unwrap()]);
+
+        let moved = _fdb.transaction(|b| {
+            b.remove("/x/two")?;
+            b.add(&File::new("/y/two").unwrap())?;
+            Ok(2)
+        });
+        assert_eq!(moved.unwrap(), 2);
+        assert_eq!(_fdb.get("two").unwrap(), vec![File::new("/y/two").unwrap()]);
+        assert!(_fdb.search(&Query::new("two")).unwrap().all(|f| f.unwrap().data.path == "/y/two"));
+    }
+
+    #[test]
+    fn rename_moves_descendants() {
+        let _fdb = Fdb::new(reset("rename"), String::from("test")).unwrap();
+        let root = reset("rename_tree");
+        fs::create_dir_all(Path::new(&root).join("old/sub")).unwrap();
+        fs::write(Path::new(&root).join("old/sub/deep.rs"), "").unwrap();
+        fs::write(Path::new(&root).join("old.txt"), "").unwrap();
+        _fdb.index_tree(&root).unwrap();
+        let key = |p: &str| Path::new(&root).join(p).to_str().unwrap().to_string();
+        let deep = _fdb.stored(&key("old/sub/deep.rs")).unwrap().unwrap();
+
+        fs::rename(Path::new(&root).join("old"), Path::new(&root).join("new")).unwrap();
+        let renamed = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(Path::new(&root).join("old"))
+            .add_path(Path::new(&root).join("new"));
+        let mut b = _fdb.batch();
+        _fdb.apply(&mut b, &Record::try_from(renamed).unwrap()).unwrap();
+        _fdb.commit(b).unwrap();
+
+        let paths: Vec<String> = _fdb.list().unwrap().into_iter().map(|f| f.data.path).collect();
+        assert_eq!(paths, vec![root.clone(), key("new"), key("new/sub"), key("new/sub/deep.rs"), key("old.txt")]);
+        let moved = _fdb.stored(&key("new/sub/deep.rs")).unwrap().unwrap();
+        assert_eq!((moved.ino, moved.mtime), (deep.ino, deep.mtime));
+        assert_eq!(_fdb.get("deep.rs").unwrap().len(), 1);
+        assert!(_fdb.get("old").unwrap().is_empty());
+        let found: Vec<String> = _fdb.search(&Query::new("new")).unwrap().map(|f| f.unwrap().name).collect();
+        assert_eq!(found, vec!["new"]);
+
+        assert_eq!(_fdb.rename(key("new/sub"), key("gone")).unwrap(), 2);
+        assert_eq!(_fdb.get("deep.rs").unwrap()[0].data.path, key("gone/deep.rs"));
+    }
+
+    #[test]
+    fn unpaired_renames_cross_the_tree_edge() {
+        let _fdb = Fdb::new(reset("rename_edge"), String::from("test")).unwrap();
+        let root = reset("rename_edge_tree");
+        let outside = reset("rename_edge_outside");
+        fs::create_dir_all(Path::new(&root).join("away/sub")).unwrap();
+        fs::write(Path::new(&root).join("away/sub/deep.rs"), "").unwrap();
+        fs::create_dir_all(Path::new(&outside).join("home/sub")).unwrap();
+        fs::write(Path::new(&outside).join("home/sub/back.rs"), "").unwrap();
+        _fdb.index_tree(&root).unwrap();
+        let key = |p: &str| Path::new(&root).join(p).to_str().unwrap().to_string();
+        let apply = |mode: RenameMode, path: PathBuf| {
+            let event = Event::new(EventKind::Modify(ModifyKind::Name(mode))).add_path(path);
+            let mut b = _fdb.batch();
+            _fdb.apply(&mut b, &Record::try_from(event).unwrap()).unwrap();
+            _fdb.commit(b).unwrap();
+        };
+
+        fs::rename(Path::new(&root).join("away"), Path::new(&outside).join("away")).unwrap();
+        apply(RenameMode::From, Path::new(&root).join("away"));
+        let paths: Vec<String> = _fdb.list().unwrap().into_iter().map(|f| f.data.path).collect();
+        assert_eq!(paths, vec![root.clone()]);
+        assert!(_fdb.get("deep.rs").unwrap().is_empty());
+        assert!(!_fdb.check("sub").unwrap());
+
+        fs::rename(Path::new(&outside).join("home"), Path::new(&root).join("home")).unwrap();
+        apply(RenameMode::To, Path::new(&root).join("home"));
+        let paths: Vec<String> = _fdb.list().unwrap().into_iter().map(|f| f.data.path).collect();
+        assert_eq!(paths, vec![root.clone(), key("home"), key("home/sub"), key("home/sub/back.rs")]);
+        assert_eq!(_fdb.get("back.rs").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reconcile_fixes_drift() {
+        let _fdb = Fdb::new(reset("reconcile"), String::from("test")).unwrap();
+        let root = reset("reconcile_tree");
+        fs::create_dir_all(Path::new(&root).join("sub")).unwrap();
+        fs::write(Path::new(&root).join("kept"), "").unwrap();
+        fs::write(Path::new(&root).join("sub/gone"), "").unwrap();
+        _fdb.index_tree(&root).unwrap();
+        _fdb.add(&File::new(format!("{}-sibling", root)).unwrap()).unwrap();
+
+        fs::remove_dir_all(Path::new(&root).join("sub")).unwrap();
+        fs::write(Path::new(&root).join("new"), "").unwrap();
+        let stats = _fdb.reconcile(&root).unwrap();
+        // The root itself counts as added or skipped depending on whether its mtime ticked.
+        assert_eq!((stats.added + stats.skipped, stats.failed, stats.removed), (3, 0, 2));
+        assert_eq!(_fdb.count().unwrap(), 4);
+        assert!(_fdb.get("gone").unwrap().is_empty());
+        assert_eq!(_fdb.get("new").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn refresh_relists_only_changed_directories() {
+        let _fdb = Fdb::new(reset("refresh"), String::from("test")).unwrap();
+        let root = reset("refresh_tree");
+        for dir in &["a/deep", "b", "c"] {
+            fs::create_dir_all(Path::new(&root).join(dir)).unwrap();
+        }
+        for file in &["a/x", "a/deep/z", "b/y", "c/w"] {
+            fs::write(Path::new(&root).join(file), "").unwrap();
+        }
+        _fdb.index_tree(&root).unwrap();
+        let key = |p: &str| match p {
+            "" => root.clone(),
+            p => Path::new(&root).join(p).to_str().unwrap().to_string(),
+        };
+        let stats = _fdb.refresh(&root).unwrap();
+        assert_eq!(stats, IndexStats { added: 0, skipped: 9, failed: 0, removed: 0 });
+
+        // Mtimes only tick once a second, so mark the changed directories by hand.
+        let stale = |p: &str| {
+            let mut data = _fdb.stored(&key(p)).unwrap().unwrap();
+            data.mtime = 0;
+            _fdb.add(&File::from_data(data).unwrap()).unwrap();
+        };
+        fs::remove_dir_all(Path::new(&root).join("b")).unwrap();
+        fs::write(Path::new(&root).join("a/new"), "").unwrap();
+        _fdb.add(&File::new(key("a/ghost")).unwrap()).unwrap();
+        _fdb.add(&File::new(key("c/phantom")).unwrap()).unwrap();
+        stale("");
+        stale("a");
+
+        // Files stored for unchanged directories are still compared one by one.
+        let stats = _fdb.refresh(&root).unwrap();
+        assert_eq!(stats, IndexStats { added: 3, skipped: 5, failed: 0, removed: 4 });
+        let paths: Vec<String> = _fdb.list().unwrap().into_iter().map(|f| f.data.path).collect();
+        let expected: Vec<String> = vec!["", "a", "a/deep", "a/deep/z", "a/new", "a/x", "c", "c/w"]
+            .into_iter()
+            .map(key)
+            .collect();
+        assert_eq!(paths, expected);
+
+        // Rewriting a file in place leaves its directory's mtime alone.
+        fs::write(Path::new(&root).join("c/w"), "rewritten").unwrap();
+        let stats = _fdb.refresh(&root).unwrap();
+        assert_eq!(stats, IndexStats { added: 1, skipped: 7, failed: 0, removed: 0 });
+        assert_eq!(_fdb.stored(&key("c/w")).unwrap().unwrap().size, 9);
+
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(_fdb.refresh(&root).unwrap().removed, 8);
+        assert_eq!(_fdb.count().unwrap(), 0);
+    }
+
+    #[test]
+    fn excluded_paths_stay_out() {
+        let mut _fdb = Fdb::new(reset("excludes"), String::from("test")).unwrap();
+        _fdb.set_excludes(Arc::new(Excludes::new(None).unwrap()));
+        let root = reset("excludes_tree");
+        fs::create_dir_all(Path::new(&root).join("target/debug")).unwrap();
+        fs::create_dir_all(Path::new(&root).join("src")).unwrap();
+        fs::write(Path::new(&root).join(".ignore"), "target/\n*.tmp\n").unwrap();
+        fs::write(Path::new(&root).join("src/main.rs"), "").unwrap();
+        fs::write(Path::new(&root).join("src/x.tmp"), "").unwrap();
+        fs::write(Path::new(&root).join("target/debug/quind"), "").unwrap();
+
+        let stats = _fdb.index_tree(&root).unwrap();
+        assert_eq!(stats.added, 4);
+        assert!(_fdb.get("quind").unwrap().is_empty());
+        assert!(_fdb.get("x.tmp").unwrap().is_empty());
+
+        let apply = |e: Event| {
+            let mut b = _fdb.batch();
+            _fdb.apply(&mut b, &Record::try_from(e).unwrap()).unwrap();
+            _fdb.commit(b).unwrap();
+        };
+        let build = Path::new(&root).join("target/debug/build");
+        fs::write(&build, "").unwrap();
+        apply(Event::new(EventKind::Create(CreateKind::File)).add_path(build));
+        assert!(_fdb.get("build").unwrap().is_empty());
+
+        let fresh = Path::new(&root).join("fresh");
+        fs::create_dir_all(fresh.join("inner")).unwrap();
+        fs::write(fresh.join("inner/late.rs"), "").unwrap();
+        fs::write(fresh.join("inner/late.tmp"), "").unwrap();
+        apply(Event::new(EventKind::Create(CreateKind::Folder)).add_path(fresh));
+        assert_eq!(_fdb.get("late.rs").unwrap().len(), 1);
+        assert!(_fdb.get("late.tmp").unwrap().is_empty());
+        assert_eq!(_fdb.count().unwrap(), 7);
+    }
 }
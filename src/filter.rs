@@ -0,0 +1,570 @@
+use crate::fdb::{File, FileKind};
+use globset::{GlobBuilder, GlobMatcher};
+use std::error;
+use std::fmt;
+use std::path::Path;
+
+/// A query that failed to parse, with the 1-based character column it failed at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub query: String,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at column {}\n  {}\n  {}^",
+            self.message,
+            self.column,
+            self.query,
+            " ".repeat(self.column - 1)
+        )
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// A predicate over stored records, parsed from expressions such as
+/// `ext:log size:>100M mtime:<2d path:/var type:f`.
+///
+/// Terms next to each other must all match; `OR` gives alternatives, `NOT` or a
+/// leading `-` negates, and parentheses group. `AND` binds tighter than `OR`. A term
+/// is `field:value` or a bare word matched against the name:
+///
+/// - `name:` and bare words match names, `path:` full paths; both ignore case. A value
+///   with `*`, `?` or `[` is a glob; a `path:` value starting with `/` is a prefix;
+///   anything else is a substring.
+/// - `ext:` takes extensions, several separated by commas.
+/// - `size:` takes a byte count with an optional `k`, `M`, `G` or `T` (powers of 1024).
+/// - `mtime:` and `ctime:` take an age in `s`, `m`, `h`, `d` or `w`, or a date as
+///   `YYYY-MM-DD`: `<2d` is newer than two days, `>2020-01-01` after that day. A bare
+///   age means "within", a bare date "on that day".
+/// - `type:` takes `f`, `d`, `l` or `o` (or `file`, `dir`, `symlink`, `other`).
+///
+/// Sizes and times take `<`, `<=`, `>`, `>=` or `=` in front of the value. Values may be
+/// quoted with `"` to include spaces or parentheses.
+#[derive(Debug)]
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Term(Term),
+}
+
+#[derive(Debug)]
+pub enum Term {
+    Name(Text),
+    Path(Text),
+    Ext(Vec<String>),
+    Size(Cmp, u64),
+    Mtime(Cmp, Time),
+    Ctime(Cmp, Time),
+    Kind(FileKind),
+}
+
+/// How a `name:` or `path:` value is compared, ignoring case.
+#[derive(Debug)]
+pub enum Text {
+    Substring(String),
+    Prefix(String),
+    Glob(GlobMatcher),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cmp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+}
+
+/// A point in time to compare timestamps with, in seconds since the Unix epoch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Time {
+    /// Newer than `now - age` when compared with `<`.
+    Age(i64),
+    /// The start of a UTC day.
+    Day(i64),
+}
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+impl Filter {
+    pub fn parse(query: &str) -> Result<Filter, ParseError> {
+        let tokens = lex(query)?;
+        let mut parser = Parser { query, tokens, pos: 0 };
+        let filter = parser.or()?;
+        match parser.peek() {
+            None => Ok(filter),
+            Some(t) => Err(parser.error(t.column, "unexpected ')'")),
+        }
+    }
+
+    /// Whether `f` matches, with ages counted back from `now` in seconds since the Unix
+    /// epoch.
+    pub fn is_match(&self, f: &File, now: i64) -> bool {
+        match self {
+            Filter::And(a, b) => a.is_match(f, now) && b.is_match(f, now),
+            Filter::Or(a, b) => a.is_match(f, now) || b.is_match(f, now),
+            Filter::Not(a) => !a.is_match(f, now),
+            Filter::Term(t) => t.is_match(f, now),
+        }
+    }
+}
+
+impl Term {
+    fn is_match(&self, f: &File, now: i64) -> bool {
+        let d = &f.data;
+        match self {
+            Term::Name(text) => text.is_match(&f.name),
+            Term::Path(text) => text.is_match(&d.path),
+            Term::Ext(exts) => Path::new(&f.name)
+                .extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| exts.iter().any(|x| x.eq_ignore_ascii_case(e))),
+            Term::Size(cmp, n) => cmp.holds(d.size, *n),
+            Term::Mtime(cmp, t) => t.holds(*cmp, d.mtime, now),
+            Term::Ctime(cmp, t) => t.holds(*cmp, d.ctime, now),
+            Term::Kind(kind) => d.kind == *kind,
+        }
+    }
+}
+
+impl Text {
+    fn is_match(&self, s: &str) -> bool {
+        match self {
+            Text::Substring(p) => s.to_lowercase().contains(p.as_str()),
+            Text::Prefix(p) => s.to_lowercase().starts_with(p.as_str()),
+            Text::Glob(glob) => glob.is_match(s),
+        }
+    }
+}
+
+impl Cmp {
+    fn holds<T: PartialOrd>(self, a: T, b: T) -> bool {
+        match self {
+            Cmp::Lt => a < b,
+            Cmp::Le => a <= b,
+            Cmp::Gt => a > b,
+            Cmp::Ge => a >= b,
+            Cmp::Eq => a == b,
+        }
+    }
+}
+
+impl Time {
+    fn holds(self, cmp: Cmp, t: i64, now: i64) -> bool {
+        match (self, cmp) {
+            // Ages grow towards the past, so `<2d` is the last two days.
+            (Time::Age(age), Cmp::Eq) => now - t <= age,
+            (Time::Age(age), cmp) => cmp.holds(now - t, age),
+            (Time::Day(day), Cmp::Eq) => t >= day && t < day + SECONDS_PER_DAY,
+            (Time::Day(day), Cmp::Gt) => t >= day + SECONDS_PER_DAY,
+            (Time::Day(day), Cmp::Le) => t < day + SECONDS_PER_DAY,
+            (Time::Day(day), cmp) => cmp.holds(t, day),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Kind {
+    Open,
+    Close,
+    /// `columns` holds the query column of each character of `text`, which quotes
+    /// leave out, then the column just past the word.
+    Word { text: String, quoted: bool, columns: Vec<usize> },
+}
+
+#[derive(Debug)]
+struct Token {
+    kind: Kind,
+    column: usize,
+}
+
+fn lex(query: &str) -> Result<Vec<Token>, ParseError> {
+    let error = |column: usize, message: &str| ParseError {
+        query: String::from(query),
+        column,
+        message: String::from(message),
+    };
+    let chars: Vec<char> = query.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let column = i + 1;
+        match chars[i] {
+            c if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push(Token { kind: Kind::Open, column });
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token { kind: Kind::Close, column });
+                i += 1;
+            }
+            _ => {
+                let mut text = String::new();
+                let mut columns = Vec::new();
+                let mut quoted = false;
+                while i < chars.len() && !chars[i].is_whitespace() && chars[i] != '(' && chars[i] != ')' {
+                    if chars[i] == '"' {
+                        let start = i;
+                        i += 1;
+                        while i < chars.len() && chars[i] != '"' {
+                            text.push(chars[i]);
+                            columns.push(i + 1);
+                            i += 1;
+                        }
+                        if i == chars.len() {
+                            return Err(error(start + 1, "unterminated quote"));
+                        }
+                        quoted = true;
+                    } else {
+                        text.push(chars[i]);
+                        columns.push(i + 1);
+                    }
+                    i += 1;
+                }
+                columns.push(i + 1);
+                tokens.push(Token {
+                    kind: Kind::Word { text, quoted, columns },
+                    column,
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    query: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, column: usize, message: &str) -> ParseError {
+        ParseError {
+            query: String::from(self.query),
+            column,
+            message: String::from(message),
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn keyword(&self, word: &str) -> bool {
+        match self.peek() {
+            Some(Token { kind: Kind::Word { text, quoted: false, .. }, .. }) => text == word,
+            _ => false,
+        }
+    }
+
+    /// The column just past the end of the query, where a missing term is reported.
+    fn end(&self) -> usize {
+        self.query.chars().count() + 1
+    }
+
+    fn or(&mut self) -> Result<Filter, ParseError> {
+        let mut left = self.and()?;
+        while self.keyword("OR") {
+            self.pos += 1;
+            let right = self.and()?;
+            left = Filter::Or(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Filter, ParseError> {
+        let mut left = self.not()?;
+        loop {
+            if self.keyword("AND") {
+                self.pos += 1;
+            } else if self.keyword("OR") || matches!(self.peek(), None | Some(Token { kind: Kind::Close, .. })) {
+                return Ok(left);
+            }
+            let right = self.not()?;
+            left = Filter::And(Box::new(left), Box::new(right));
+        }
+    }
+
+    fn not(&mut self) -> Result<Filter, ParseError> {
+        if self.keyword("NOT") {
+            self.pos += 1;
+            return Ok(Filter::Not(Box::new(self.not()?)));
+        }
+        if let Some(Token { kind: Kind::Word { text, quoted: false, columns }, .. }) = self.peek() {
+            if text.len() > 1 && text.starts_with('-') {
+                let (text, columns) = (text[1..].to_string(), columns[1..].to_vec());
+                self.pos += 1;
+                return Ok(Filter::Not(Box::new(Filter::Term(self.term(&text, &columns)?))));
+            }
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<Filter, ParseError> {
+        let (kind, column) = match self.tokens.get(self.pos) {
+            Some(t) => (&t.kind, t.column),
+            None => return Err(self.error(self.end(), "expected a term")),
+        };
+        match kind {
+            Kind::Open => {
+                self.pos += 1;
+                let inner = self.or()?;
+                match self.peek() {
+                    Some(Token { kind: Kind::Close, .. }) => {
+                        self.pos += 1;
+                        Ok(inner)
+                    }
+                    _ => Err(self.error(column, "unclosed '('")),
+                }
+            }
+            Kind::Close => Err(self.error(column, "expected a term before ')'")),
+            Kind::Word { text, columns, .. } => {
+                let (text, columns) = (text.clone(), columns.clone());
+                self.pos += 1;
+                Ok(Filter::Term(self.term(&text, &columns)?))
+            }
+        }
+    }
+
+    /// Parses `word`, whose characters sit at `columns` in the query.
+    fn term(&self, word: &str, columns: &[usize]) -> Result<Term, ParseError> {
+        let column = columns[0];
+        let (field, value) = match word.find(':') {
+            Some(i) => (&word[..i], &word[i + 1..]),
+            None => return Ok(Term::Name(self.text(word, column, false)?)),
+        };
+        let columns = &columns[field.chars().count() + 1..];
+        let at = columns[0];
+        if value.is_empty() {
+            return Err(self.error(at, &format!("'{}:' needs a value", field)));
+        }
+        match field {
+            "name" => Ok(Term::Name(self.text(value, at, false)?)),
+            "path" => Ok(Term::Path(self.text(value, at, true)?)),
+            "ext" => Ok(Term::Ext(
+                value.split(',').map(|e| e.trim_start_matches('.').to_string()).collect(),
+            )),
+            "size" => {
+                let (cmp, rest, columns) = comparison(value, columns);
+                Ok(Term::Size(cmp, self.size(rest, columns)?))
+            }
+            "mtime" | "ctime" => {
+                let (cmp, rest, columns) = comparison(value, columns);
+                let time = self.time(rest, columns)?;
+                match field {
+                    "mtime" => Ok(Term::Mtime(cmp, time)),
+                    _ => Ok(Term::Ctime(cmp, time)),
+                }
+            }
+            "type" => match value {
+                "f" | "file" => Ok(Term::Kind(FileKind::File)),
+                "d" | "dir" => Ok(Term::Kind(FileKind::Dir)),
+                "l" | "symlink" => Ok(Term::Kind(FileKind::Symlink)),
+                "o" | "other" => Ok(Term::Kind(FileKind::Other)),
+                _ => Err(self.error(at, "type must be one of f, d, l, o")),
+            },
+            _ => Err(self.error(
+                column,
+                &format!("unknown field '{}', expected name, path, ext, size, mtime, ctime or type", field),
+            )),
+        }
+    }
+
+    fn text(&self, value: &str, column: usize, path: bool) -> Result<Text, ParseError> {
+        if value.contains(['*', '?', '[']) {
+            return GlobBuilder::new(value)
+                .case_insensitive(true)
+                .build()
+                .map(|g| Text::Glob(g.compile_matcher()))
+                .map_err(|e| self.error(column, &format!("bad glob: {}", e.kind())));
+        }
+        match path && value.starts_with('/') {
+            true => Ok(Text::Prefix(value.to_lowercase())),
+            false => Ok(Text::Substring(value.to_lowercase())),
+        }
+    }
+
+    fn size(&self, value: &str, columns: &[usize]) -> Result<u64, ParseError> {
+        let column = columns[0];
+        let (n, unit) = split_number(value);
+        let at = columns[n.chars().count()];
+        let n: u64 = n.parse().map_err(|_| self.error(column, "expected a size such as 100M"))?;
+        let shift = match unit.to_lowercase().as_str() {
+            "" | "b" => 0,
+            "k" | "kb" | "kib" => 10,
+            "m" | "mb" | "mib" => 20,
+            "g" | "gb" | "gib" => 30,
+            "t" | "tb" | "tib" => 40,
+            _ => return Err(self.error(at, "size unit must be one of k, M, G, T")),
+        };
+        n.checked_mul(1 << shift).ok_or_else(|| self.error(column, "size is too large"))
+    }
+
+    fn time(&self, value: &str, columns: &[usize]) -> Result<Time, ParseError> {
+        if let Some(day) = parse_date(value) {
+            return Ok(Time::Day(day));
+        }
+        let column = columns[0];
+        let (n, unit) = split_number(value);
+        let at = columns[n.chars().count()];
+        let n: i64 = n
+            .parse()
+            .map_err(|_| self.error(column, "expected an age such as 2d or a date such as 2020-01-31"))?;
+        let seconds = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 3600,
+            "d" => SECONDS_PER_DAY,
+            "w" => 7 * SECONDS_PER_DAY,
+            _ => return Err(self.error(at, "age unit must be one of s, m, h, d, w")),
+        };
+        n.checked_mul(seconds)
+            .map(Time::Age)
+            .ok_or_else(|| self.error(column, "age is too large"))
+    }
+}
+
+/// The comparison at the start of `value`, the rest, and the columns of the rest.
+fn comparison<'a>(value: &'a str, columns: &'a [usize]) -> (Cmp, &'a str, &'a [usize]) {
+    for (prefix, cmp) in &[("<=", Cmp::Le), (">=", Cmp::Ge), ("<", Cmp::Lt), (">", Cmp::Gt), ("=", Cmp::Eq)] {
+        if let Some(rest) = value.strip_prefix(prefix) {
+            return (*cmp, rest, &columns[prefix.len()..]);
+        }
+    }
+    (Cmp::Eq, value, columns)
+}
+
+/// Splits `value` into its leading digits and the rest.
+fn split_number(value: &str) -> (&str, &str) {
+    let end = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    value.split_at(end)
+}
+
+/// The start of the UTC day written as `YYYY-MM-DD`, in seconds since the Unix epoch.
+fn parse_date(value: &str) -> Option<i64> {
+    let parts: Vec<&str> = value.split('-').collect();
+    if parts.len() != 3 || parts[0].len() != 4 || parts[1].len() != 2 || parts[2].len() != 2 {
+        return None;
+    }
+    let y: i64 = parts[0].parse().ok()?;
+    let m: i64 = parts[1].parse().ok()?;
+    let d: i64 = parts[2].parse().ok()?;
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+        return None;
+    }
+    // Days from civil, after Howard Hinnant's algorithm.
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some((era * 146_097 + doe - 719_468) * SECONDS_PER_DAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fdb::FileData;
+
+    const NOW: i64 = 1_600_000_000;
+
+    fn file(path: &str, kind: FileKind, size: u64, age: i64) -> File {
+        File::from_data(FileData {
+            path: String::from(path),
+            kind,
+            size,
+            mtime: NOW - age,
+            ctime: NOW - age,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn matches(query: &str, f: &File) -> bool {
+        Filter::parse(query).unwrap().is_match(f, NOW)
+    }
+
+    fn error(query: &str) -> (usize, String) {
+        let e = Filter::parse(query).unwrap_err();
+        (e.column, e.message)
+    }
+
+    #[test]
+    fn terms_and_metadata() {
+        let log = file("/var/log/syslog.log", FileKind::File, 200 << 20, 3600);
+        let old = file("/var/log/old.LOG", FileKind::File, 10, 10 * SECONDS_PER_DAY);
+        let dir = file("/var/log", FileKind::Dir, 4096, 60);
+
+        let query = "ext:log size:>100M mtime:<2d path:/var type:f";
+        assert!(matches(query, &log));
+        assert!(!matches(query, &old));
+        assert!(matches("ext:log,txt mtime:>1w", &old));
+        assert!(matches("syslog", &log));
+        assert!(matches("name:*.LOG", &log));
+        assert!(!matches("path:/log", &log));
+        assert!(matches("path:log/sys", &log));
+        assert!(matches("type:d size:4k", &dir));
+        assert!(matches("size:<=4096 size:>=4096", &dir));
+        assert!(matches("mtime:1h", &dir) && !matches("mtime:1h", &old));
+    }
+
+    #[test]
+    fn boolean_operators_and_grouping() {
+        let rs = file("/src/main.rs", FileKind::File, 10, 0);
+        let md = file("/src/README.md", FileKind::File, 10, 0);
+
+        assert!(matches("ext:rs OR ext:md", &md));
+        assert!(!matches("ext:rs AND ext:md", &md));
+        assert!(matches("NOT ext:rs", &md));
+        assert!(!matches("-ext:rs", &rs));
+        assert!(matches("(ext:rs OR ext:md) path:/src", &rs));
+        assert!(!matches("ext:md OR ext:txt path:/src main", &rs));
+        assert!(matches("NOT (ext:txt OR size:>1k) main", &rs));
+        assert!(matches("name:\"main.rs\"", &rs));
+    }
+
+    #[test]
+    fn dates_are_whole_utc_days() {
+        assert_eq!(parse_date("1970-01-02"), Some(SECONDS_PER_DAY));
+        assert_eq!(parse_date("2020-09-13"), Some(1_599_955_200));
+        assert_eq!(parse_date("2020-13-01"), None);
+        let f = file("/a", FileKind::File, 0, 0);
+        assert!(matches("mtime:2020-09-13", &f));
+        assert!(matches("mtime:>2020-09-12 mtime:<=2020-09-13 mtime:<2020-09-14", &f));
+        assert!(!matches("mtime:>2020-09-13", &f));
+    }
+
+    #[test]
+    fn errors_point_at_the_column() {
+        assert_eq!(error("ext:log colour:red").0, 9);
+        assert_eq!(error("size:>100Q"), (10, String::from("size unit must be one of k, M, G, T")));
+        assert_eq!(error("mtime:<soon").0, 8);
+        assert_eq!(error("type:x").0, 6);
+        assert_eq!(error("(ext:rs").0, 1);
+        assert_eq!(error("ext:rs )").0, 8);
+        assert_eq!(error("ext:rs OR").0, 10);
+        assert_eq!(error("name:\"a b").0, 6);
+        assert_eq!(error("size:").0, 6);
+        assert_eq!(error("size:>99999999999T"), (7, String::from("size is too large")));
+        assert_eq!(error("mtime:<9999999999999999w"), (8, String::from("age is too large")));
+
+        // Columns count characters of the query, quotes included.
+        assert_eq!(error("naïve size:>5ü"), (14, String::from("size unit must be one of k, M, G, T")));
+        assert_eq!(error("size:>\"10ü\""), (10, String::from("size unit must be one of k, M, G, T")));
+        assert_eq!(error("mtime:<\"5 x\"").0, 10);
+        assert_eq!(error("size:\"ünï\"").0, 7);
+
+        let e = Filter::parse("ext:rs size:huge").unwrap_err();
+        assert_eq!(e.to_string(), "expected a size such as 100M at column 13\n  ext:rs size:huge\n              ^");
+    }
+}
@@ -0,0 +1,313 @@
+use crate::fdb::{File, IndexStats};
+use crate::search::Query;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error as TError;
+
+#[cfg(unix)]
+pub use self::unix::{Client, Server, Serving};
+
+/// Version of the protocol spoken over the socket, raised on incompatible changes.
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, TError)]
+pub enum Error {
+    #[error("IO error: {0}")]
+    IO(#[from] io::Error),
+
+    #[error("Json error: {0}")]
+    JSON(#[from] serde_json::Error),
+
+    #[error("{} is in use by a running daemon", .0.display())]
+    InUse(PathBuf),
+
+    #[error("{} is not a socket; refusing to replace it", .0.display())]
+    NotSocket(PathBuf),
+
+    #[error("the daemon closed the connection")]
+    Closed,
+
+    #[error("unexpected answer from the daemon")]
+    Unexpected,
+
+    #[error("{message}")]
+    Remote { message: String, code: i32 },
+}
+
+/// One line sent to the daemon: a JSON object with the protocol `version` and the
+/// fields of a `Call`, such as `{"version":1,"call":"add_root","path":"/src"}`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Request {
+    pub version: u32,
+    #[serde(flatten)]
+    pub call: Call,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "call", rename_all = "snake_case")]
+pub enum Call {
+    Search { query: Query },
+    Status,
+    /// Indexes and watches a root until the daemon stops, keeping its entries in
+    /// `collection`, or else the configured one.
+    AddRoot {
+        path: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        collection: Option<String>,
+    },
+    /// Indexes a root once, keeping its entries in `collection`, or else the
+    /// configured one, without watching it.
+    Index {
+        path: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        collection: Option<String>,
+    },
+    /// Stops watching a root and drops its entries.
+    RemoveRoot { path: PathBuf },
+}
+
+/// One line sent back for each request.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Response {
+    /// Matches of a search, with the cursor for the next page if it was cut short.
+    Files {
+        files: Vec<File>,
+        #[serde(default)]
+        next: Option<String>,
+    },
+    Status { db: PathBuf, entries: usize, roots: Vec<PathBuf> },
+    Added { root: PathBuf, stats: IndexStats },
+    Indexed { root: PathBuf, stats: IndexStats },
+    Removed { root: PathBuf, entries: usize },
+    /// The request failed; `code` is the exit status the CLI reports for it.
+    Error { message: String, code: i32 },
+}
+
+impl Request {
+    pub fn new(call: Call) -> Request {
+        Request { version: VERSION, call }
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+
+    use crate::error::EXIT_USAGE;
+    use log::warn;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+
+    /// A connection to a running daemon.
+    pub struct Client {
+        reader: BufReader<UnixStream>,
+        writer: UnixStream,
+    }
+
+    impl Client {
+        /// Connects to the daemon listening on `path`, or returns `None` if none is.
+        pub fn connect<P: AsRef<Path>>(path: P) -> Option<Client> {
+            let stream = UnixStream::connect(path).ok()?;
+            let writer = stream.try_clone().ok()?;
+            Some(Client {
+                reader: BufReader::new(stream),
+                writer,
+            })
+        }
+
+        /// Sends `call` and waits for its answer. Failures reported by the daemon come
+        /// back as `Error::Remote`.
+        pub fn call(&mut self, call: Call) -> Result<Response, Error> {
+            let mut line = serde_json::to_string(&Request::new(call))?;
+            line.push('\n');
+            self.writer.write_all(line.as_bytes())?;
+            let mut answer = String::new();
+            if self.reader.read_line(&mut answer)? == 0 {
+                return Err(Error::Closed);
+            }
+            match serde_json::from_str(&answer)? {
+                Response::Error { message, code } => Err(Error::Remote { message, code }),
+                response => Ok(response),
+            }
+        }
+    }
+
+    /// A listening socket, removed again when dropped.
+    pub struct Server {
+        path: PathBuf,
+        listener: UnixListener,
+    }
+
+    /// A `Server` answering on its own thread.
+    pub struct Serving {
+        path: PathBuf,
+        stop: Arc<AtomicBool>,
+        thread: JoinHandle<()>,
+    }
+
+    impl Server {
+        /// Listens on `path`, replacing a socket left behind by a daemon that is gone.
+        /// Anything else found there is left alone.
+        pub fn bind<P: AsRef<Path>>(path: P) -> Result<Server, Error> {
+            let path = path.as_ref().to_path_buf();
+            if let Ok(m) = fs::symlink_metadata(&path) {
+                if !m.file_type().is_socket() {
+                    return Err(Error::NotSocket(path));
+                }
+                if UnixStream::connect(&path).is_ok() {
+                    return Err(Error::InUse(path));
+                }
+                fs::remove_file(&path)?;
+            }
+            let listener = UnixListener::bind(&path)?;
+            Ok(Server { path, listener })
+        }
+
+        /// Answers every request with `handle`, one thread per connection, until
+        /// `Serving::stop` is called.
+        pub fn spawn<H>(self, handle: H) -> Serving
+        where
+            H: Fn(Call) -> Response + Send + Sync + 'static,
+        {
+            let stop = Arc::new(AtomicBool::new(false));
+            let path = self.path.clone();
+            let stopped = Arc::clone(&stop);
+            let handle = Arc::new(handle);
+            let thread = thread::spawn(move || {
+                for stream in self.listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    match stream {
+                        Ok(stream) => {
+                            let handle = Arc::clone(&handle);
+                            thread::spawn(move || {
+                                if let Err(e) = serve(stream, &*handle) {
+                                    warn!("{}", e);
+                                }
+                            });
+                        }
+                        Err(e) => warn!("{}", e),
+                    }
+                }
+                drop(self);
+            });
+            Serving { path, stop, thread }
+        }
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    impl Serving {
+        /// Stops taking connections and removes the socket. Requests in progress on
+        /// open connections are left to finish.
+        pub fn stop(self) {
+            self.stop.store(true, Ordering::SeqCst);
+            // Wakes the listener blocked in accept.
+            let _ = UnixStream::connect(&self.path);
+            let _ = self.thread.join();
+        }
+    }
+
+    fn serve(stream: UnixStream, handle: &dyn Fn(Call) -> Response) -> Result<(), Error> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let response = match serde_json::from_str::<Request>(&line?) {
+                Ok(r) if r.version == VERSION => handle(r.call),
+                Ok(r) => Response::Error {
+                    message: format!("protocol version {} is not supported, this daemon speaks {}", r.version, VERSION),
+                    code: EXIT_USAGE,
+                },
+                Err(e) => Response::Error {
+                    message: format!("bad request: {}", e),
+                    code: EXIT_USAGE,
+                },
+            };
+            let mut answer = serde_json::to_string(&response)?;
+            answer.push('\n');
+            writer.write_all(answer.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn requests_are_flat_json_objects() {
+        let request = Request::new(Call::AddRoot { path: PathBuf::from("/src"), collection: None });
+        let line = serde_json::to_string(&request).unwrap();
+        assert_eq!(line, r#"{"version":1,"call":"add_root","path":"/src"}"#);
+        assert_eq!(serde_json::from_str::<Request>(&line).unwrap(), request);
+
+        let request = Request::new(Call::Index { path: PathBuf::from("/src"), collection: Some(String::from("src")) });
+        let line = serde_json::to_string(&request).unwrap();
+        assert_eq!(line, r#"{"version":1,"call":"index","path":"/src","collection":"src"}"#);
+        assert_eq!(serde_json::from_str::<Request>(&line).unwrap(), request);
+
+        let line = r#"{"version":1,"call":"search","query":{"pattern":"mdrs","mode":"fuzzy","target":"path","ignore_case":false,"limit":5}}"#;
+        match serde_json::from_str::<Request>(line).unwrap().call {
+            Call::Search { query } => assert_eq!(query.limit, Some(5)),
+            call => panic!("unexpected {:?}", call),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn server_answers_until_stopped() {
+        let dir = Path::new("./instance/ipc");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let path = dir.join("quind.sock");
+
+        let serving = Server::bind(&path).unwrap().spawn(|call| match call {
+            Call::Status => Response::Status { db: PathBuf::from("/db"), entries: 3, roots: Vec::new() },
+            _ => Response::Error { message: String::from("no"), code: 7 },
+        });
+        assert!(matches!(Server::bind(&path), Err(Error::InUse(_))));
+
+        let mut client = Client::connect(&path).unwrap();
+        match client.call(Call::Status).unwrap() {
+            Response::Status { entries, .. } => assert_eq!(entries, 3),
+            response => panic!("unexpected {:?}", response),
+        }
+        match client.call(Call::RemoveRoot { path: PathBuf::from("/") }) {
+            Err(Error::Remote { code, .. }) => assert_eq!(code, 7),
+            result => panic!("unexpected {:?}", result),
+        }
+
+        serving.stop();
+        assert!(!path.exists());
+        assert!(Client::connect(&path).is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn binding_leaves_other_files_alone() {
+        let dir = Path::new("./instance/ipc_file");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let path = dir.join("notes.txt");
+        fs::write(&path, "keep me").unwrap();
+
+        assert!(matches!(Server::bind(&path), Err(Error::NotSocket(_))));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep me");
+    }
+}
@@ -1,8 +1,67 @@
 extern crate lazy_static;
 extern crate serde_json;
 
+pub mod cli;
+pub mod codec;
+pub mod config;
+pub mod daemon;
 pub mod error;
+pub mod exclude;
 pub mod fdb;
+pub mod filter;
+pub mod ipc;
 pub mod monitor;
+pub mod output;
+pub mod record;
+pub mod search;
 
-fn main() -> () {}
+use clap::ErrorKind;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::process;
+
+/// Writes log messages at or above the current maximum level to stderr.
+struct Stderr;
+
+impl Log for Stderr {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("quind: {}: {}", record.level().to_string().to_lowercase(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Stderr = Stderr;
+
+fn main() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Warn);
+    }
+    let matches = match cli::app().get_matches_safe() {
+        Ok(m) => m,
+        Err(e) => match e.kind {
+            ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => {
+                println!("{}", e.message);
+                process::exit(error::EXIT_OK);
+            }
+            _ => {
+                eprintln!("{}", e.message);
+                process::exit(error::EXIT_USAGE);
+            }
+        },
+    };
+
+    let code = match cli::run(&matches) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("quind: {}", e);
+            e.exit_code()
+        }
+    };
+    process::exit(code);
+}
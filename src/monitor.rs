@@ -1,7 +1,14 @@
+use crate::exclude::Excludes;
+use log::warn;
+use notify::event::{EventKind, ModifyKind, RenameMode};
 use notify::{Watcher, RecommendedWatcher, RecursiveMode, Event, Config};
-use std::path::{Path};
-use std::sync::mpsc;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
 use thiserror::Error as TError;
+use walkdir::WalkDir;
 
 #[derive(Debug, TError)]
 pub enum Error {
@@ -15,6 +22,239 @@ pub enum Error {
 pub struct Monitor {
     watcher: Box<RecommendedWatcher>,
     rx: mpsc::Receiver<Result<Event, notify::Error>>,
+    roots: Vec<PathBuf>,
+    excludes: Option<Arc<Excludes>>,
+    watched: BTreeSet<PathBuf>,
+    renames: Renames,
+    debouncer: Option<Debouncer>,
+}
+
+/// How long the first half of a rename is held waiting for the second.
+const RENAME_WINDOW: Duration = Duration::from_millis(50);
+
+/// Trackers of recent pairs remembered to drop the backend's own `Both` event.
+const PAIRED_TRACKERS: usize = 64;
+
+/// Pairs the `Name(From)` and `Name(To)` halves of a rename, linked by their tracker,
+/// into one `Modify(Name(Both))` event whose paths are `[from, to]`.
+///
+/// A `From` with no `To` within `window` left the watched tree and is passed on alone,
+/// as is a `To` with no `From`. Backends that report `Both` themselves after the halves
+/// are not reported twice. Every other event is passed on as it comes.
+pub struct Renames {
+    window: Duration,
+    from: Vec<(usize, Event, Instant)>,
+    paired: VecDeque<usize>,
+    ready: VecDeque<Event>,
+}
+
+impl Renames {
+    pub fn new(window: Duration) -> Renames {
+        Renames {
+            window,
+            from: Vec::new(),
+            paired: VecDeque::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Takes in `e`, received at `now`.
+    pub fn push(&mut self, e: Event, now: Instant) {
+        let (mode, tracker) = match (&e.kind, e.tracker()) {
+            (EventKind::Modify(ModifyKind::Name(mode)), Some(tracker)) => (mode.clone(), tracker),
+            _ => return self.ready.push_back(e),
+        };
+        let from = self.from.iter().position(|(t, _, _)| *t == tracker);
+        match (mode, from) {
+            (RenameMode::From, _) if e.paths.len() == 1 => self.from.push((tracker, e, now)),
+            (RenameMode::To, Some(i)) if e.paths.len() == 1 => {
+                let (_, from, _) = self.from.remove(i);
+                let both = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+                    .add_path(from.paths[0].clone())
+                    .add_path(e.paths[0].clone())
+                    .set_tracker(tracker);
+                if self.paired.len() == PAIRED_TRACKERS {
+                    self.paired.pop_front();
+                }
+                self.paired.push_back(tracker);
+                self.ready.push_back(both);
+            }
+            (RenameMode::Both, _) if self.paired.contains(&tracker) => {
+                self.paired.retain(|t| *t != tracker);
+            }
+            (RenameMode::Both, Some(i)) => {
+                self.from.remove(i);
+                self.ready.push_back(e);
+            }
+            _ => self.ready.push_back(e),
+        }
+    }
+
+    /// The next event that is ready at `now`, oldest first.
+    pub fn pop(&mut self, now: Instant) -> Option<Event> {
+        let window = self.window;
+        while let Some(i) = self.from.iter().position(|(_, _, at)| now.duration_since(*at) >= window) {
+            let (_, from, _) = self.from.remove(i);
+            self.ready.push_back(from);
+        }
+        self.ready.pop_front()
+    }
+
+    /// When the oldest unpaired `From` is given up on, or `None` if there is none.
+    pub fn deadline(&self) -> Option<Instant> {
+        if !self.ready.is_empty() {
+            return Some(Instant::now());
+        }
+        self.from.iter().map(|(_, _, at)| *at + self.window).min()
+    }
+}
+
+/// Net effect of the events seen for one path.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Net {
+    Create,
+    Modify,
+    Remove,
+}
+
+impl Net {
+    /// The effect `kind` has on a single path, or `None` for events that are passed on
+    /// untouched. Renames keep their tracker, so they are never merged.
+    fn of(kind: &EventKind) -> Option<Net> {
+        match kind {
+            EventKind::Create(_) => Some(Net::Create),
+            EventKind::Modify(ModifyKind::Name(_)) => None,
+            EventKind::Modify(_) => Some(Net::Modify),
+            EventKind::Remove(_) => Some(Net::Remove),
+            _ => None,
+        }
+    }
+
+    /// What `self` followed by `next` amounts to; `None` when they cancel out.
+    fn then(self, next: Net) -> Option<Net> {
+        match (self, next) {
+            (Net::Create, Net::Remove) => None,
+            (Net::Create, _) => Some(Net::Create),
+            (Net::Modify, Net::Remove) => Some(Net::Remove),
+            (Net::Modify, _) => Some(Net::Modify),
+            (Net::Remove, Net::Remove) => Some(Net::Remove),
+            // Removed and recreated: the path exists but may differ from before.
+            (Net::Remove, _) => Some(Net::Modify),
+        }
+    }
+}
+
+struct Pending {
+    net: Net,
+    event: Event,
+    first: Instant,
+    last: Instant,
+    seq: u64,
+}
+
+/// How many windows a path that never goes quiet is held for at most.
+const MAX_WINDOWS: u32 = 10;
+
+/// Merges the events for each path that arrive within `window` of each other into one
+/// net effect: create then modify is a create, create then remove is nothing, and
+/// repeated modifies are one. A path is released once it has been quiet for `window`,
+/// or `MAX_WINDOWS` windows after its first event if it is written to all along.
+///
+/// Events that touch several paths, renames, and other kinds are passed on as they
+/// come, after anything still pending for their paths.
+pub struct Debouncer {
+    window: Duration,
+    pending: HashMap<PathBuf, Pending>,
+    ready: VecDeque<Event>,
+    seq: u64,
+}
+
+impl Debouncer {
+    pub fn new(window: Duration) -> Debouncer {
+        Debouncer {
+            window,
+            pending: HashMap::new(),
+            ready: VecDeque::new(),
+            seq: 0,
+        }
+    }
+
+    /// Takes in `e`, received at `now`.
+    pub fn push(&mut self, e: Event, now: Instant) {
+        let net = match (e.paths.len(), Net::of(&e.kind)) {
+            (1, Some(net)) => net,
+            _ => {
+                for p in &e.paths {
+                    if let Some(pending) = self.pending.remove(p) {
+                        self.ready.push_back(pending.event);
+                    }
+                }
+                self.ready.push_back(e);
+                return;
+            }
+        };
+        let path = e.paths[0].clone();
+        let mut first = now;
+        let merged = match self.pending.remove(&path) {
+            None => Some((net, e)),
+            Some(old) => old.net.then(net).map(|merged| {
+                first = old.first;
+                if merged == old.net {
+                    (merged, old.event)
+                } else if merged == net {
+                    (merged, e)
+                } else {
+                    (merged, Event::new(EventKind::Modify(ModifyKind::Any)).add_path(path.clone()))
+                }
+            }),
+        };
+        if let Some((net, event)) = merged {
+            self.seq += 1;
+            let seq = self.seq;
+            self.pending.insert(path, Pending { net, event, first, last: now, seq });
+        }
+    }
+
+    /// The next event that is ready at `now`, oldest first.
+    pub fn pop(&mut self, now: Instant) -> Option<Event> {
+        if self.ready.is_empty() {
+            let window = self.window;
+            let max = self.max_delay();
+            let mut quiet: Vec<PathBuf> = self
+                .pending
+                .iter()
+                .filter(|(_, p)| now.duration_since(p.last) >= window || now.duration_since(p.first) >= max)
+                .map(|(path, _)| path.clone())
+                .collect();
+            quiet.sort_by_key(|path| self.pending[path].seq);
+            for path in quiet {
+                if let Some(p) = self.pending.remove(&path) {
+                    self.ready.push_back(p.event);
+                }
+            }
+        }
+        self.ready.pop_front()
+    }
+
+    /// When the next pending event becomes ready, or `None` if nothing is pending.
+    pub fn deadline(&self) -> Option<Instant> {
+        if !self.ready.is_empty() {
+            return Some(Instant::now());
+        }
+        let max = self.max_delay();
+        self.pending.values().map(|p| (p.last + self.window).min(p.first + max)).min()
+    }
+
+    /// The longest a path stays pending, however often it changes.
+    fn max_delay(&self) -> Duration {
+        self.window * MAX_WINDOWS
+    }
+}
+
+impl Default for Monitor {
+    fn default() -> Monitor {
+        Monitor::new()
+    }
 }
 
 impl Monitor {
@@ -22,13 +262,27 @@ impl Monitor {
     pub fn new() -> Monitor {
         let (tx, rx) = mpsc::channel();
 
-        let mut watcher: Result<RecommendedWatcher, notify::Error> = Watcher::new_immediate(move |result| {
+        let watcher: Result<RecommendedWatcher, notify::Error> = Watcher::new_immediate(move |result| {
             tx.send(result).unwrap();
         });
 
         Monitor {
             watcher: Box::new(watcher.unwrap()),
             rx,
+            roots: Vec::new(),
+            excludes: None,
+            watched: BTreeSet::new(),
+            renames: Renames::new(RENAME_WINDOW),
+            debouncer: None,
+        }
+    }
+
+    /// Merges events per path over `window` before `get` returns them; see `Debouncer`.
+    /// Changing the window keeps the events already pending.
+    pub fn debounce(&mut self, window: Duration) {
+        match &mut self.debouncer {
+            Some(d) => d.window = window,
+            None => self.debouncer = Some(Debouncer::new(window)),
         }
     }
 
@@ -40,13 +294,26 @@ impl Monitor {
         }
     }
 
+    /// Leaves excluded directories unwatched. Roots watched from then on get one watch
+    /// per kept directory instead of a recursive one, and directories that appear later
+    /// are added as their events come in.
+    pub fn set_excludes(&mut self, excludes: Arc<Excludes>) {
+        self.excludes = Some(excludes);
+    }
+
     pub fn watch<P>(&mut self, d: P) -> Result<(), Error>
     where
         P: AsRef<Path>
     {
-        let _watch = self.watcher.watch(d, RecursiveMode::Recursive);
+        let _watch = match self.excludes {
+            Some(_) => self.watch_tree(d.as_ref()),
+            None => self.watcher.watch(d.as_ref(), RecursiveMode::Recursive),
+        };
         match _watch {
-            Ok(()) => Ok(()),
+            Ok(()) => {
+                self.roots.push(d.as_ref().to_path_buf());
+                Ok(())
+            }
             Err(error) => Err(Error::Notify(error)),
         }
     }
@@ -55,25 +322,202 @@ impl Monitor {
         where
             P: AsRef<Path>
     {
-        let _unwatch = self.watcher.unwatch(d);
+        let _unwatch = match self.excludes {
+            Some(_) => self.unwatch_tree(d.as_ref()),
+            None => self.watcher.unwatch(d.as_ref()),
+        };
         match _unwatch {
-            Ok(()) => Ok(()),
+            Ok(()) => {
+                self.roots.retain(|r| r != d.as_ref());
+                Ok(())
+            }
             Err(error) => Err(Error::Notify(error)),
         }
     }
 
+    /// Watches `dir` and every directory below it that is not excluded, one by one.
+    /// Only a failure on `dir` itself is an error; the rest are logged.
+    fn watch_tree(&mut self, dir: &Path) -> Result<(), notify::Error> {
+        let excludes = match &self.excludes {
+            Some(e) => Arc::clone(e),
+            None => return Ok(()),
+        };
+        let dirs = WalkDir::new(dir)
+            .into_iter()
+            .filter_entry(|e| e.depth() == 0 || !excludes.matches(e.path(), e.file_type().is_dir()))
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_dir());
+        for entry in dirs {
+            match self.watcher.watch(entry.path(), RecursiveMode::NonRecursive) {
+                Ok(()) => {
+                    self.watched.insert(entry.into_path());
+                }
+                Err(e) if entry.depth() == 0 => return Err(e),
+                Err(e) => warn!("{}: {}", entry.path().display(), e),
+            }
+        }
+        Ok(())
+    }
+
+    fn unwatch_tree(&mut self, dir: &Path) -> Result<(), notify::Error> {
+        let below: Vec<PathBuf> = self.watched.iter().filter(|w| w.starts_with(dir)).cloned().collect();
+        let mut result = Ok(());
+        for w in below {
+            self.watched.remove(&w);
+            match self.watcher.unwatch(&w) {
+                Err(e) if w == dir => result = Err(e),
+                // Watches on deleted directories are already gone.
+                _ => {}
+            }
+        }
+        result
+    }
+
+    /// Keeps per-directory watches in step with directories created, moved or removed.
+    fn track(&mut self, e: &Event) {
+        let excludes = match &self.excludes {
+            Some(excludes) => Arc::clone(excludes),
+            None => return,
+        };
+        let gone = match &e.kind {
+            EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => e.paths.first(),
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => e.paths.first(),
+            _ => None,
+        };
+        if let Some(gone) = gone {
+            if self.watched.contains(gone) {
+                let _ = self.unwatch_tree(gone);
+            }
+        }
+        let new = match &e.kind {
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => e.paths.last(),
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => e.paths.last(),
+            _ => None,
+        };
+        if let Some(new) = new {
+            let is_dir = fs::symlink_metadata(new).map(|m| m.is_dir()).unwrap_or(false);
+            let inside = self.roots.iter().any(|r| new.starts_with(r));
+            if is_dir && inside && !self.watched.contains(new) && !excludes.is_excluded(new, true) {
+                if let Err(err) = self.watch_tree(new) {
+                    warn!("{}: {}", new.display(), err);
+                }
+            }
+        }
+    }
+
+    /// The watched roots that hold any of `paths`, or every root when none does or no
+    /// paths are given.
+    pub fn roots_of(&self, paths: &[PathBuf]) -> Vec<PathBuf> {
+        let affected: Vec<PathBuf> = self
+            .roots
+            .iter()
+            .filter(|r| paths.iter().any(|p| p.starts_with(r)))
+            .cloned()
+            .collect();
+        match affected.is_empty() {
+            true => self.roots.clone(),
+            false => affected,
+        }
+    }
+
+    /// Waits for the next event, with renames paired and, if enabled, debounced.
     pub fn get(&mut self) -> Result<Result<Event, notify::Error>, Error> {
-        match self.rx.recv() {
-            Ok(event) => Ok(event),
-            Err(e) => Err(Error::Sync(e)),
+        loop {
+            if let Some(event) = self.receive(None)? {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Like `get`, but gives up and returns `None` once `timeout` has passed.
+    pub fn get_timeout(&mut self, timeout: Duration) -> Result<Option<Result<Event, notify::Error>>, Error> {
+        self.receive(Some(Instant::now() + timeout))
+    }
+
+    fn receive(&mut self, until: Option<Instant>) -> Result<Option<Result<Event, notify::Error>>, Error> {
+        loop {
+            let now = Instant::now();
+            if let Some(event) = self.next_ready(now) {
+                return Ok(Some(Ok(event)));
+            }
+            let deadline = match (self.deadline(), until) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            let received = match deadline {
+                Some(t) => self.rx.recv_timeout(t.saturating_duration_since(now)),
+                None => self.rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(Ok(event)) => {
+                    self.track(&event);
+                    self.renames.push(event, Instant::now());
+                }
+                Ok(Err(e)) => return Ok(Some(Err(e))),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Err(Error::Sync(mpsc::RecvError)),
+            }
+            if until.is_some_and(|t| Instant::now() >= t) {
+                return Ok(self.next_ready(Instant::now()).map(Ok));
+            }
+        }
+    }
+
+    /// Returns the next queued event without waiting, if there is one.
+    pub fn try_get(&mut self) -> Option<Result<Event, notify::Error>> {
+        while let Ok(received) = self.rx.try_recv() {
+            match received {
+                Ok(event) => {
+                    self.track(&event);
+                    self.renames.push(event, Instant::now());
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        self.next_ready(Instant::now()).map(Ok)
+    }
+
+    /// Returns every event received so far without waiting, including those still
+    /// held back for rename pairing or debouncing, so nothing is lost on shutdown.
+    pub fn flush(&mut self) -> Vec<Result<Event, notify::Error>> {
+        let mut events = Vec::new();
+        while let Some(event) = self.try_get() {
+            events.push(event);
+        }
+        while let Some(t) = self.deadline() {
+            match self.next_ready(t) {
+                Some(event) => events.push(Ok(event)),
+                None => break,
+            }
+        }
+        events
+    }
+
+    fn next_ready(&mut self, now: Instant) -> Option<Event> {
+        while let Some(event) = self.renames.pop(now) {
+            match &mut self.debouncer {
+                Some(d) => d.push(event, now),
+                None => return Some(event),
+            }
+        }
+        self.debouncer.as_mut().and_then(|d| d.pop(now))
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        let debounced = self.debouncer.as_ref().and_then(|d| d.deadline());
+        match (self.renames.deadline(), debounced) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
         }
     }
 }
 
 #[cfg(test)]
+#[allow(dead_code, unused_must_use, clippy::unused_unit, clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
+    use notify::event::{CreateKind, DataChange, RemoveKind, RenameMode};
     use std::fs;
     use std::fs::File;
     use std::path::Path;
@@ -116,6 +560,198 @@ mod tests {
         //assert_eq!(m.test_out().is_ok(), false);
         //rm(&Path::new("./test"));
     }
+
+    #[test]
+    fn get_times_out_when_idle() {
+        let mut m = monitor_init();
+        let start = Instant::now();
+        assert!(m.get_timeout(Duration::from_millis(20)).unwrap().is_none());
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    fn at(base: Instant, ms: u64) -> Instant {
+        base + Duration::from_millis(ms)
+    }
+
+    fn event(kind: &EventKind, path: &str) -> Event {
+        Event::new(kind.clone()).add_path(PathBuf::from(path))
+    }
+
+    fn drain(d: &mut Debouncer, now: Instant) -> Vec<(EventKind, Vec<PathBuf>)> {
+        let mut out = Vec::new();
+        while let Some(e) = d.pop(now) {
+            out.push((e.kind, e.paths));
+        }
+        out
+    }
+
+    #[test]
+    fn debounce_merges_per_path() {
+        let t = Instant::now();
+        let create = EventKind::Create(CreateKind::File);
+        let modify = EventKind::Modify(ModifyKind::Data(DataChange::Content));
+        let remove = EventKind::Remove(RemoveKind::File);
+        let mut d = Debouncer::new(Duration::from_millis(50));
+
+        d.push(event(&create, "/a"), at(t, 0));
+        d.push(event(&modify, "/a"), at(t, 10));
+        d.push(event(&modify, "/b"), at(t, 10));
+        d.push(event(&modify, "/b"), at(t, 20));
+        d.push(event(&modify, "/b"), at(t, 30));
+        d.push(event(&create, "/c"), at(t, 30));
+        d.push(event(&remove, "/c"), at(t, 40));
+        d.push(event(&remove, "/d"), at(t, 40));
+        d.push(event(&create, "/d"), at(t, 45));
+        assert_eq!(
+            drain(&mut d, at(t, 100)),
+            vec![
+                (create, vec![PathBuf::from("/a")]),
+                (modify, vec![PathBuf::from("/b")]),
+                (EventKind::Modify(ModifyKind::Any), vec![PathBuf::from("/d")]),
+            ]
+        );
+        assert_eq!(d.deadline(), None);
+    }
+
+    #[test]
+    fn debounce_waits_for_a_quiet_window() {
+        let t = Instant::now();
+        let modify = EventKind::Modify(ModifyKind::Any);
+        let mut d = Debouncer::new(Duration::from_millis(50));
+
+        d.push(event(&modify, "/a"), at(t, 0));
+        d.push(event(&modify, "/a"), at(t, 40));
+        assert!(d.pop(at(t, 60)).is_none());
+        assert_eq!(d.deadline(), Some(at(t, 90)));
+        assert_eq!(drain(&mut d, at(t, 90)).len(), 1);
+    }
+
+    #[test]
+    fn debounce_releases_busy_paths_after_the_max_delay() {
+        let t = Instant::now();
+        let modify = EventKind::Modify(ModifyKind::Any);
+        let mut d = Debouncer::new(Duration::from_millis(50));
+
+        for ms in (0..=500).step_by(40) {
+            d.push(event(&modify, "/busy"), at(t, ms));
+            if ms < 480 {
+                assert!(d.pop(at(t, ms)).is_none());
+            }
+        }
+        assert_eq!(d.deadline(), Some(at(t, 500)));
+        assert_eq!(drain(&mut d, at(t, 500)), vec![(modify.clone(), vec![PathBuf::from("/busy")])]);
+
+        // The next change starts a new delay.
+        d.push(event(&modify, "/busy"), at(t, 520));
+        assert!(d.pop(at(t, 540)).is_none());
+        assert_eq!(d.deadline(), Some(at(t, 570)));
+    }
+
+    #[test]
+    fn debounce_passes_renames_through_in_order() {
+        let t = Instant::now();
+        let modify = EventKind::Modify(ModifyKind::Any);
+        let rename = EventKind::Modify(ModifyKind::Name(RenameMode::Both));
+        let mut d = Debouncer::new(Duration::from_millis(50));
+
+        d.push(event(&modify, "/a"), at(t, 0));
+        d.push(event(&rename, "/a").add_path(PathBuf::from("/b")), at(t, 10));
+        assert_eq!(
+            drain(&mut d, at(t, 10)),
+            vec![
+                (modify, vec![PathBuf::from("/a")]),
+                (rename, vec![PathBuf::from("/a"), PathBuf::from("/b")]),
+            ]
+        );
+    }
+    fn half(mode: RenameMode, path: &str, tracker: usize) -> Event {
+        event(&EventKind::Modify(ModifyKind::Name(mode)), path).set_tracker(tracker)
+    }
+
+    #[test]
+    fn renames_are_paired_by_tracker() {
+        let t = Instant::now();
+        let both = EventKind::Modify(ModifyKind::Name(RenameMode::Both));
+        let mut r = Renames::new(Duration::from_millis(50));
+
+        r.push(half(RenameMode::From, "/a", 1), at(t, 0));
+        r.push(half(RenameMode::From, "/c", 2), at(t, 0));
+        assert!(r.pop(at(t, 10)).is_none());
+        r.push(half(RenameMode::To, "/d", 2), at(t, 10));
+        r.push(half(RenameMode::To, "/b", 1), at(t, 10));
+        r.push(event(&both, "/a").add_path(PathBuf::from("/b")).set_tracker(1), at(t, 10));
+
+        let paired: Vec<(EventKind, Vec<PathBuf>, Option<usize>)> = vec![r.pop(at(t, 10)), r.pop(at(t, 10))]
+            .into_iter()
+            .map(|e| e.unwrap())
+            .map(|e| (e.kind.clone(), e.paths.clone(), e.tracker()))
+            .collect();
+        assert_eq!(
+            paired,
+            vec![
+                (both.clone(), vec![PathBuf::from("/c"), PathBuf::from("/d")], Some(2)),
+                (both, vec![PathBuf::from("/a"), PathBuf::from("/b")], Some(1)),
+            ]
+        );
+        assert!(r.pop(at(t, 100)).is_none());
+    }
+
+    #[test]
+    fn unpaired_halves_pass_through() {
+        let t = Instant::now();
+        let mut r = Renames::new(Duration::from_millis(50));
+
+        r.push(half(RenameMode::To, "/in", 3), at(t, 0));
+        assert_eq!(r.pop(at(t, 0)).unwrap().paths, vec![PathBuf::from("/in")]);
+
+        r.push(half(RenameMode::From, "/out", 4), at(t, 0));
+        assert_eq!(r.deadline(), Some(at(t, 50)));
+        assert!(r.pop(at(t, 49)).is_none());
+        let out = r.pop(at(t, 50)).unwrap();
+        assert_eq!(out.kind, EventKind::Modify(ModifyKind::Name(RenameMode::From)));
+        assert_eq!(r.deadline(), None);
+    }
+    #[test]
+    fn rescans_cover_the_affected_roots() {
+        let mut m = monitor_init();
+        m.watch("./src").unwrap();
+        m.watch("./").unwrap();
+        let src = PathBuf::from("./src");
+        let all = vec![src.clone(), PathBuf::from("./")];
+        assert_eq!(m.roots_of(&[]), all);
+        assert_eq!(m.roots_of(&[PathBuf::from("/elsewhere")]), all);
+        assert_eq!(m.roots_of(&[PathBuf::from("./Cargo.toml")]), vec![PathBuf::from("./")]);
+        assert_eq!(m.roots_of(&[PathBuf::from("./src/main.rs")]), all);
+        m.unwatch("./").unwrap();
+        assert_eq!(m.roots_of(&[]), vec![src]);
+    }
+
+    #[test]
+    fn excluded_directories_are_not_watched() {
+        let root = Path::new("./instance/monitor_excludes");
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root.join("src/deep")).unwrap();
+        fs::create_dir_all(root.join("target/debug")).unwrap();
+        fs::write(root.join(".ignore"), "target/\n").unwrap();
+        let root = fs::canonicalize(root).unwrap();
+
+        let mut m = monitor_init();
+        m.set_excludes(Arc::new(Excludes::new(None).unwrap()));
+        m.watch(&root).unwrap();
+        let watched: Vec<PathBuf> = m.watched.iter().cloned().collect();
+        assert_eq!(watched, vec![root.clone(), root.join("src"), root.join("src/deep")]);
+
+        mkdir(&root.join("new"));
+        m.track(&Event::new(EventKind::Create(notify::event::CreateKind::Folder)).add_path(root.join("new")));
+        assert!(m.watched.contains(&root.join("new")));
+        rmdir(&root.join("src"));
+        m.track(&Event::new(EventKind::Remove(notify::event::RemoveKind::Folder)).add_path(root.join("src")));
+        assert!(!m.watched.iter().any(|w| w.starts_with(root.join("src"))));
+
+        m.unwatch(&root).unwrap();
+        assert!(m.watched.is_empty());
+    }
+
     // Event: Ok(Event {
     //   kind: Create(Any),
     //   paths: ["D:\\Projects\\quind\\./test"],
@@ -0,0 +1,168 @@
+use crate::fdb::{File, FileKind};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// How search results are written.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Format {
+    /// One path per line.
+    Plain,
+    /// Paths each ended by a NUL byte, for `xargs -0`.
+    Null,
+    /// One serialized `File` per line (NDJSON).
+    Json,
+    /// The chosen fields of each record, separated by tabs, one record per line.
+    Columns(Vec<Column>),
+}
+
+/// A field of a record that `Format::Columns` can show.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Column {
+    Name,
+    Path,
+    Kind,
+    Size,
+    Mtime,
+    Ctime,
+    Mode,
+    Uid,
+    Gid,
+    Dev,
+    Ino,
+}
+
+const COLUMNS: &[(&str, Column)] = &[
+    ("name", Column::Name),
+    ("path", Column::Path),
+    ("kind", Column::Kind),
+    ("size", Column::Size),
+    ("mtime", Column::Mtime),
+    ("ctime", Column::Ctime),
+    ("mode", Column::Mode),
+    ("uid", Column::Uid),
+    ("gid", Column::Gid),
+    ("dev", Column::Dev),
+    ("ino", Column::Ino),
+];
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Column, String> {
+        match COLUMNS.iter().find(|(name, _)| *name == s) {
+            Some((_, column)) => Ok(*column),
+            None => {
+                let names: Vec<&str> = COLUMNS.iter().map(|(name, _)| *name).collect();
+                Err(format!("unknown column '{}', expected one of {}", s, names.join(", ")))
+            }
+        }
+    }
+}
+
+impl Column {
+    /// Parses a comma-separated list such as `path,size,mtime`.
+    pub fn parse_list(list: &str) -> Result<Vec<Column>, String> {
+        list.split(',').map(|c| c.trim().parse()).collect()
+    }
+
+    /// The value of this field in `f`. Times are seconds since the Unix epoch and the
+    /// mode is the permission bits in octal.
+    pub fn value(self, f: &File) -> String {
+        let d = &f.data;
+        match self {
+            Column::Name => f.name.clone(),
+            Column::Path => d.path.clone(),
+            Column::Kind => String::from(match d.kind {
+                FileKind::File => "file",
+                FileKind::Dir => "dir",
+                FileKind::Symlink => "symlink",
+                FileKind::Other => "other",
+            }),
+            Column::Size => d.size.to_string(),
+            Column::Mtime => d.mtime.to_string(),
+            Column::Ctime => d.ctime.to_string(),
+            Column::Mode => format!("{:04o}", d.mode & 0o7777),
+            Column::Uid => d.uid.to_string(),
+            Column::Gid => d.gid.to_string(),
+            Column::Dev => d.dev.to_string(),
+            Column::Ino => d.ino.to_string(),
+        }
+    }
+}
+
+/// Writes `files` to `out` in `format` and returns how many there were.
+pub fn write<W, I>(out: &mut W, format: &Format, files: I) -> io::Result<usize>
+where
+    W: Write,
+    I: IntoIterator<Item = File>,
+{
+    let mut count = 0;
+    for f in files {
+        match format {
+            Format::Plain => writeln!(out, "{}", f.data.path)?,
+            Format::Null => write!(out, "{}\0", f.data.path)?,
+            Format::Json => {
+                serde_json::to_writer(&mut *out, &f)?;
+                writeln!(out)?;
+            }
+            Format::Columns(columns) => {
+                let values: Vec<String> = columns.iter().map(|c| c.value(&f)).collect();
+                writeln!(out, "{}", values.join("\t"))?;
+            }
+        }
+        count += 1;
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fdb::FileData;
+
+    fn files() -> Vec<File> {
+        let data = FileData {
+            path: String::from("/src/main.rs"),
+            kind: FileKind::File,
+            size: 1200,
+            mtime: 1_600_000_000,
+            mode: 0o100644,
+            ..Default::default()
+        };
+        vec![
+            File::from_data(data).unwrap(),
+            File::new("/src/a b").unwrap(),
+        ]
+    }
+
+    fn written(format: Format) -> String {
+        let mut out = Vec::new();
+        assert_eq!(write(&mut out, &format, files()).unwrap(), 2);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn plain_and_null() {
+        assert_eq!(written(Format::Plain), "/src/main.rs\n/src/a b\n");
+        assert_eq!(written(Format::Null), "/src/main.rs\0/src/a b\0");
+    }
+
+    #[test]
+    fn json_lines_are_files() {
+        let text = written(Format::Json);
+        let lines: Vec<File> = text.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines, files());
+    }
+
+    #[test]
+    fn chosen_columns() {
+        let columns = Column::parse_list("path, size,mtime,mode,kind").unwrap();
+        assert_eq!(
+            written(Format::Columns(columns)),
+            "/src/main.rs\t1200\t1600000000\t0644\tfile\n/src/a b\t0\t0\t0000\tother\n"
+        );
+        assert!(Column::parse_list("path,colour").unwrap_err().contains("'colour'"));
+    }
+}
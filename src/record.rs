@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+#[cfg(not(unix))]
+use std::io;
+#[cfg(not(unix))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What kind of directory entry a record describes.
+#[derive(Clone, Copy, Default, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    #[default]
+    Other,
+}
+
+impl From<fs::FileType> for FileKind {
+    fn from(t: fs::FileType) -> FileKind {
+        if t.is_symlink() {
+            FileKind::Symlink
+        } else if t.is_dir() {
+            FileKind::Dir
+        } else if t.is_file() {
+            FileKind::File
+        } else {
+            FileKind::Other
+        }
+    }
+}
+
+/// A stored record. Timestamps are seconds since the Unix epoch; fields missing
+/// from records written before they existed read back as zero.
+#[derive(Clone, Default, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(default)]
+pub struct FileData {
+    pub path: String,
+    pub kind: FileKind,
+    pub size: u64,
+    pub mtime: i64,
+    pub ctime: i64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub dev: u64,
+    pub ino: u64,
+}
+
+impl FileData {
+    #[cfg(unix)]
+    pub(crate) fn fill(&mut self, m: &fs::Metadata) {
+        use std::os::unix::fs::MetadataExt;
+
+        self.kind = FileKind::from(m.file_type());
+        self.size = m.len();
+        self.mtime = m.mtime();
+        self.ctime = m.ctime();
+        self.mode = m.mode();
+        self.uid = m.uid();
+        self.gid = m.gid();
+        self.dev = m.dev();
+        self.ino = m.ino();
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn fill(&mut self, m: &fs::Metadata) {
+        fn seconds(t: io::Result<SystemTime>) -> i64 {
+            t.ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs() as i64)
+        }
+
+        self.kind = FileKind::from(m.file_type());
+        self.size = m.len();
+        self.mtime = seconds(m.modified());
+        self.ctime = seconds(m.created());
+    }
+}
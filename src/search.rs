@@ -0,0 +1,616 @@
+use crate::fdb::{Error, File};
+use crate::filter::Filter;
+use globset::{GlobBuilder, GlobMatcher};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How a query pattern is compared with a candidate.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Substring,
+    Prefix,
+    Glob,
+    Regex,
+    /// Ordered subsequence of the full path, ranked by `Matcher::score`.
+    Fuzzy,
+}
+
+/// Which part of a record a query pattern is compared with.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    Name,
+    Path,
+}
+
+/// What results are ordered by. Ties are broken by path, so every order is total.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    Name,
+    /// The order records are stored in, which lets results stream straight from the store.
+    Path,
+    Size,
+    Mtime,
+    /// The `Matcher::score`, then the shorter path. Only fuzzy queries rank.
+    Relevance,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Query {
+    pub pattern: String,
+    pub mode: Mode,
+    pub target: Target,
+    pub ignore_case: bool,
+    pub limit: Option<usize>,
+    /// An expression over record metadata that results must match too; see `Filter`.
+    #[serde(default)]
+    pub filter: Option<String>,
+    /// Relevance for fuzzy queries and path for the rest when unset.
+    #[serde(default)]
+    pub sort: Option<Sort>,
+    /// Descending for relevance, so the best come first, and ascending for the rest
+    /// when unset.
+    #[serde(default)]
+    pub order: Option<Order>,
+    /// How many results to skip, counted after `after`.
+    #[serde(default)]
+    pub offset: usize,
+    /// A token from `Query::cursor`; only results ordered after it are returned.
+    #[serde(default)]
+    pub after: Option<String>,
+    /// Names of the collections to search; all of them when empty.
+    #[serde(default)]
+    pub collections: Vec<String>,
+}
+
+/// What a cursor token holds: the order it was made for and where its result fell.
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    sort: Sort,
+    order: Order,
+    key: i64,
+    path: String,
+}
+
+impl Query {
+    /// A case-sensitive substring query over names.
+    pub fn new<S: Into<String>>(pattern: S) -> Query {
+        Query {
+            pattern: pattern.into(),
+            mode: Mode::Substring,
+            target: Target::Name,
+            ignore_case: false,
+            limit: None,
+            filter: None,
+            sort: None,
+            order: None,
+            offset: 0,
+            after: None,
+            collections: Vec::new(),
+        }
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Query {
+        self.mode = mode;
+        self
+    }
+
+    pub fn target(mut self, target: Target) -> Query {
+        self.target = target;
+        self
+    }
+
+    pub fn ignore_case(mut self, yes: bool) -> Query {
+        self.ignore_case = yes;
+        self
+    }
+
+    /// Caps the number of results returned.
+    pub fn limit(mut self, n: usize) -> Query {
+        self.limit = Some(n);
+        self
+    }
+
+    /// Keeps only results that also match `expr`, such as `ext:log size:>100M`.
+    pub fn filter<S: Into<String>>(mut self, expr: S) -> Query {
+        self.filter = Some(expr.into());
+        self
+    }
+
+    pub fn sort(mut self, key: Sort) -> Query {
+        self.sort = Some(key);
+        self
+    }
+
+    pub fn order(mut self, order: Order) -> Query {
+        self.order = Some(order);
+        self
+    }
+
+    /// Skips the first `n` results.
+    pub fn offset(mut self, n: usize) -> Query {
+        self.offset = n;
+        self
+    }
+
+    /// Resumes after the result `cursor` was made for; see `Query::cursor`.
+    pub fn after<S: Into<String>>(mut self, cursor: S) -> Query {
+        self.after = Some(cursor.into());
+        self
+    }
+
+    /// Searches the collection `name` too, rather than every collection.
+    pub fn collection<S: Into<String>>(mut self, name: S) -> Query {
+        self.collections.push(name.into());
+        self
+    }
+
+    /// The key and direction results come in, once defaults are applied.
+    pub fn ordering(&self) -> (Sort, Order) {
+        let sort = self.sort.unwrap_or(match self.mode {
+            Mode::Fuzzy => Sort::Relevance,
+            _ => Sort::Path,
+        });
+        let order = self.order.unwrap_or(match sort {
+            Sort::Relevance => Order::Desc,
+            _ => Order::Asc,
+        });
+        (sort, order)
+    }
+
+    /// Compares two results, each with its score, in the order the query asks for.
+    pub fn compare(&self, a: &(i64, File), b: &(i64, File)) -> Ordering {
+        let (sort, order) = self.ordering();
+        let ((sa, a), (sb, b)) = (a, b);
+        let (pa, pb) = (&a.data.path, &b.data.path);
+        let ordering = match sort {
+            Sort::Name => a.name.cmp(&b.name).then(pa.cmp(pb)),
+            Sort::Path => pa.cmp(pb),
+            Sort::Size => a.data.size.cmp(&b.data.size).then(pa.cmp(pb)),
+            Sort::Mtime => a.data.mtime.cmp(&b.data.mtime).then(pa.cmp(pb)),
+            // Reversed below, so the shorter, then the earlier, path wins a tie.
+            Sort::Relevance => sa.cmp(sb).then(pb.len().cmp(&pa.len())).then(pb.cmp(pa)),
+        };
+        match order {
+            Order::Asc => ordering,
+            Order::Desc => ordering.reverse(),
+        }
+    }
+
+    /// A token that, passed to `Query::after`, resumes this query's results after `f`,
+    /// which scored `score`. Records added or changed in the meantime do not shift
+    /// the pages that follow.
+    pub fn cursor(&self, score: i64, f: &File) -> String {
+        let (sort, order) = self.ordering();
+        let key = match sort {
+            Sort::Name | Sort::Path => 0,
+            Sort::Size => f.data.size.min(i64::MAX as u64) as i64,
+            Sort::Mtime => f.data.mtime,
+            Sort::Relevance => score,
+        };
+        let cursor = Cursor {
+            sort,
+            order,
+            key,
+            path: f.data.path.clone(),
+        };
+        let json = serde_json::to_vec(&cursor).unwrap_or_default();
+        json.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// The result `after` was made for, with its score, failing if the token is
+    /// malformed or was made for another order.
+    pub fn resume(&self) -> Result<Option<(i64, File)>, Error> {
+        let token = match &self.after {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        let invalid = || Error::Cursor(format!("'{}' is not a cursor", token));
+        if token.len() % 2 != 0 || !token.is_ascii() {
+            return Err(invalid());
+        }
+        let bytes = (0..token.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&token[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid())?;
+        let cursor: Cursor = serde_json::from_slice(&bytes).map_err(|_| invalid())?;
+        if (cursor.sort, cursor.order) != self.ordering() {
+            return Err(Error::Cursor(String::from("the cursor was made for another sort order")));
+        }
+        let mut f = File::new(&cursor.path)?;
+        f.data.size = cursor.key.max(0) as u64;
+        f.data.mtime = cursor.key;
+        Ok(Some((cursor.key, f)))
+    }
+
+    /// Compiles the query, failing if the pattern is not valid for its mode or the
+    /// filter does not parse.
+    pub fn matcher(&self) -> Result<Matcher, Error> {
+        let pattern = match self.ignore_case {
+            true => self.pattern.to_lowercase(),
+            false => self.pattern.clone(),
+        };
+        let inner = match self.mode {
+            Mode::Substring => Inner::Substring(pattern),
+            Mode::Prefix => Inner::Prefix(pattern),
+            Mode::Glob => {
+                // Relative path globs such as `src/**/mod.rs` may match anywhere in the tree.
+                let glob = match self.target {
+                    Target::Path if !self.pattern.starts_with('/') && !self.pattern.starts_with("**") => {
+                        format!("**/{}", self.pattern)
+                    }
+                    _ => self.pattern.clone(),
+                };
+                let glob = GlobBuilder::new(&glob)
+                    .case_insensitive(self.ignore_case)
+                    .literal_separator(true)
+                    .build()?;
+                Inner::Glob(glob.compile_matcher())
+            }
+            Mode::Regex => Inner::Regex(
+                RegexBuilder::new(&self.pattern)
+                    .case_insensitive(self.ignore_case)
+                    .build()?,
+            ),
+            // Smart case: an uppercase letter in the pattern makes it case-sensitive.
+            Mode::Fuzzy => match self.ignore_case || !self.pattern.chars().any(char::is_uppercase) {
+                true => Inner::Fuzzy(self.pattern.to_lowercase().chars().collect(), true),
+                false => Inner::Fuzzy(self.pattern.chars().collect(), false),
+            },
+        };
+        let filter = match &self.filter {
+            Some(expr) => Some(Filter::parse(expr)?),
+            None => None,
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        Ok(Matcher {
+            inner,
+            target: self.target,
+            ignore_case: self.ignore_case,
+            filter,
+            now,
+        })
+    }
+
+    /// Substrings every match must contain, ignoring case. Empty when nothing is known,
+    /// for instance for regexes with alternations or groups.
+    pub fn literals(&self) -> Vec<String> {
+        let literals = match self.mode {
+            Mode::Substring | Mode::Prefix => vec![self.pattern.clone()],
+            Mode::Glob => glob_literals(&self.pattern),
+            Mode::Regex => regex_literals(&self.pattern),
+            Mode::Fuzzy => Vec::new(),
+        };
+        literals
+            .into_iter()
+            .filter(|l| !l.is_empty())
+            .map(|l| l.to_lowercase())
+            .collect()
+    }
+}
+
+/// The distinct lowercase three-character windows of `s`.
+pub fn trigrams(s: &str) -> BTreeSet<String> {
+    let chars: Vec<char> = s.to_lowercase().chars().collect();
+    chars.windows(3).map(|w| w.iter().collect()).collect()
+}
+
+fn glob_literals(pattern: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut cur = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => return Vec::new(),
+            '\\' => cur.extend(chars.next()),
+            '*' | '?' | '[' | '/' => {
+                out.push(cur.split_off(0));
+                if c == '[' {
+                    chars.by_ref().find(|&c| c == ']');
+                }
+            }
+            c => cur.push(c),
+        }
+    }
+    out.push(cur);
+    out
+}
+
+fn regex_literals(pattern: &str) -> Vec<String> {
+    // Only plain concatenations are understood; anything that can make a run optional
+    // as a whole gives up rather than risk dropping a match.
+    if pattern.contains('|') || pattern.contains('(') {
+        return Vec::new();
+    }
+    let mut out = Vec::new();
+    let mut cur = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(e) if e.is_ascii_punctuation() => cur.push(e),
+                Some(e) => {
+                    out.push(cur.split_off(0));
+                    // Code points and classes carry an argument, either braced or of a
+                    // fixed length, which is not literal text.
+                    let len = match e {
+                        'x' => 2,
+                        'u' => 4,
+                        'U' => 8,
+                        'p' | 'P' => 1,
+                        _ => 0,
+                    };
+                    if len > 0 && chars.clone().next() == Some('{') {
+                        chars.by_ref().find(|&c| c == '}');
+                    } else {
+                        chars.by_ref().take(len).for_each(drop);
+                    }
+                }
+                None => out.push(cur.split_off(0)),
+            },
+            // The preceding character may be absent, so it cannot be required.
+            '*' | '?' | '{' => {
+                cur.pop();
+                out.push(cur.split_off(0));
+                if c == '{' {
+                    chars.by_ref().find(|&c| c == '}');
+                }
+            }
+            '+' => {
+                let last = cur.chars().last();
+                out.push(cur.split_off(0));
+                cur.extend(last);
+            }
+            '[' => {
+                out.push(cur.split_off(0));
+                chars.by_ref().find(|&c| c == ']');
+            }
+            '.' | '^' | '$' => out.push(cur.split_off(0)),
+            c => cur.push(c),
+        }
+    }
+    out.push(cur);
+    out
+}
+
+enum Inner {
+    Substring(String),
+    Prefix(String),
+    Glob(GlobMatcher),
+    Regex(Regex),
+    Fuzzy(Vec<char>, bool),
+}
+
+/// A compiled `Query`.
+pub struct Matcher {
+    inner: Inner,
+    target: Target,
+    ignore_case: bool,
+    filter: Option<Filter>,
+    /// When the query was compiled, which filter ages count back from.
+    now: i64,
+}
+
+impl Matcher {
+    pub fn is_match(&self, f: &File) -> bool {
+        self.score(f).is_some()
+    }
+
+    /// How well `f` matches, higher being better, or `None` if it does not match.
+    /// Only fuzzy queries rank their results; every other match scores zero.
+    pub fn score(&self, f: &File) -> Option<i64> {
+        if let Some(filter) = &self.filter {
+            if !filter.is_match(f, self.now) {
+                return None;
+            }
+        }
+        let candidate = match self.target {
+            Target::Name => f.name.as_str(),
+            Target::Path => f.data.path.as_str(),
+        };
+        let matched = match &self.inner {
+            Inner::Substring(p) => self.fold(candidate).contains(p.as_str()),
+            Inner::Prefix(p) => self.fold(candidate).starts_with(p.as_str()),
+            Inner::Glob(glob) => glob.is_match(candidate),
+            Inner::Regex(re) => re.is_match(candidate),
+            Inner::Fuzzy(p, fold) => return fuzzy_score(p, &f.data.path, *fold),
+        };
+        match matched {
+            true => Some(0),
+            false => None,
+        }
+    }
+
+    fn fold<'a>(&self, s: &'a str) -> Cow<'a, str> {
+        match self.ignore_case {
+            true => Cow::Owned(s.to_lowercase()),
+            false => Cow::Borrowed(s),
+        }
+    }
+}
+
+const SCORE_MATCH: i64 = 16;
+const BONUS_CONSECUTIVE: i64 = 16;
+const BONUS_BOUNDARY: i64 = 12;
+const BONUS_NAME: i64 = 8;
+const PENALTY_GAP_START: i64 = 3;
+const PENALTY_GAP: i64 = 1;
+
+/// Scores `pattern` as an ordered subsequence of `path`, picking the placement with the
+/// best total. Each hit earns a base score plus bonuses for following the previous hit,
+/// starting a word and falling inside the final path component; skipped characters
+/// between hits cost a penalty.
+fn fuzzy_score(pattern: &[char], path: &str, fold: bool) -> Option<i64> {
+    let original: Vec<char> = path.chars().collect();
+    let text: Vec<char> = match fold {
+        true => path.to_lowercase().chars().collect(),
+        false => original.clone(),
+    };
+    // Lowercasing may change the length of some characters; those paths are not ranked.
+    if pattern.is_empty() || text.len() != original.len() {
+        return None;
+    }
+    let name_start = original.iter().rposition(|&c| c == '/').map_or(0, |i| i + 1);
+    let bonus = |j: usize| -> i64 {
+        let mut b = SCORE_MATCH;
+        let boundary = j == 0
+            || matches!(original[j - 1], '/' | '_' | '-' | '.' | ' ')
+            || (original[j - 1].is_lowercase() && original[j].is_uppercase());
+        if boundary {
+            b += BONUS_BOUNDARY;
+        }
+        if j >= name_start {
+            b += BONUS_NAME;
+        }
+        b
+    };
+
+    // best[j] is the top score of the pattern so far with its last character at j.
+    let mut best: Vec<Option<i64>> = text
+        .iter()
+        .enumerate()
+        .map(|(j, &c)| match c == pattern[0] {
+            true => Some(bonus(j)),
+            false => None,
+        })
+        .collect();
+    for &pc in &pattern[1..] {
+        let mut next = vec![None; text.len()];
+        // Highest best[k] + k * PENALTY_GAP over k < j - 1, so gaps cost linear time.
+        let mut gapped: Option<i64> = None;
+        for j in 1..text.len() {
+            if j >= 2 {
+                if let Some(s) = best[j - 2] {
+                    let s = s + (j as i64 - 2) * PENALTY_GAP;
+                    gapped = Some(gapped.map_or(s, |g| g.max(s)));
+                }
+            }
+            if text[j] != pc {
+                continue;
+            }
+            let adjacent = best[j - 1].map(|s| s + BONUS_CONSECUTIVE);
+            let apart = gapped.map(|g| g - PENALTY_GAP_START - (j as i64 - 1) * PENALTY_GAP);
+            next[j] = match (adjacent, apart) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                (a, b) => a.or(b),
+            }
+            .map(|s| s + bonus(j));
+        }
+        best = next;
+    }
+    best.into_iter().flatten().max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(q: Query, path: &str) -> bool {
+        q.matcher().unwrap().is_match(&File::new(path).unwrap())
+    }
+
+    #[test]
+    fn substring_and_prefix() {
+        assert!(matches(Query::new("onit"), "/src/monitor.rs"));
+        assert!(!matches(Query::new("src"), "/src/monitor.rs"));
+        assert!(matches(Query::new("src").target(Target::Path), "/src/monitor.rs"));
+        assert!(matches(Query::new("mon").mode(Mode::Prefix), "/src/monitor.rs"));
+        assert!(!matches(Query::new("itor").mode(Mode::Prefix), "/src/monitor.rs"));
+    }
+
+    #[test]
+    fn ignore_case() {
+        assert!(!matches(Query::new("readme"), "/README.md"));
+        assert!(matches(Query::new("readme").ignore_case(true), "/README.md"));
+        assert!(matches(Query::new("*.MD").mode(Mode::Glob).ignore_case(true), "/README.md"));
+    }
+
+    #[test]
+    fn glob() {
+        let rs = || Query::new("*.rs").mode(Mode::Glob);
+        assert!(matches(rs(), "/src/fdb.rs"));
+        assert!(!matches(rs(), "/src/fdb.rs.orig"));
+
+        let nested = || Query::new("src/**/mod.rs").mode(Mode::Glob).target(Target::Path);
+        assert!(matches(nested(), "/repo/src/a/b/mod.rs"));
+        assert!(matches(nested(), "/repo/src/mod.rs"));
+        assert!(!matches(nested(), "/repo/lib/mod.rs"));
+        assert!(!matches(Query::new("/src/*.rs").mode(Mode::Glob).target(Target::Path), "/src/a/b.rs"));
+    }
+
+    #[test]
+    fn regex() {
+        let tests = || Query::new(r"test_.*\.py$").mode(Mode::Regex);
+        assert!(matches(tests(), "/app/test_views.py"));
+        assert!(!matches(tests(), "/app/test_views.pyc"));
+        assert!(!matches(tests(), "/test_app/views.py"));
+        assert!(matches(tests().target(Target::Path), "/test_app/x.py"));
+        assert!(matches(Query::new("^TEST_").mode(Mode::Regex).ignore_case(true), "/app/test_a.py"));
+    }
+
+    #[test]
+    fn fuzzy_ranking() {
+        let score = |p: &str, path: &str| Query::new(p).mode(Mode::Fuzzy).matcher().unwrap().score(&File::new(path).unwrap());
+        assert!(score("mnrs", "/src/monitor.rs").is_some());
+        assert!(score("mnrs", "/src/main.py").is_none());
+        assert!(score("srm", "/src/monitor.rs").is_some());
+        // Hits in the name beat hits in directories.
+        assert!(score("mon", "/src/monitor.rs") > score("mon", "/mon/src/x.rs"));
+        // Consecutive hits beat scattered ones.
+        assert!(score("fdb", "/src/fdb.rs") > score("fdb", "/src/fxdxb.rs"));
+        // Word starts beat hits in the middle of a word.
+        assert!(score("rs", "/src/read_state") > score("rs", "/src/xrxs"));
+        // Smart case.
+        assert!(score("Mon", "/src/monitor.rs").is_none());
+        assert!(score("mon", "/src/Monitor.rs").is_some());
+    }
+
+    #[test]
+    fn trigram_windows() {
+        let t: Vec<String> = trigrams("Main.rs").into_iter().collect();
+        assert_eq!(t, vec![".rs", "ain", "in.", "mai", "n.r"]);
+        assert!(trigrams("ab").is_empty());
+    }
+
+    #[test]
+    fn required_literals() {
+        let literals = |q: Query| q.literals();
+        assert_eq!(literals(Query::new("Monitor")), vec!["monitor"]);
+        assert_eq!(literals(Query::new("*.rs").mode(Mode::Glob)), vec![".rs"]);
+        assert_eq!(literals(Query::new("fo[ox]bar?.t*").mode(Mode::Glob)), vec!["fo", "bar", ".t"]);
+        assert!(literals(Query::new("{a,b}.rs").mode(Mode::Glob)).is_empty());
+        assert_eq!(literals(Query::new(r"test_.*\.py$").mode(Mode::Regex)), vec!["test_", ".py"]);
+        assert_eq!(literals(Query::new(r"abcd*e+f").mode(Mode::Regex)), vec!["abc", "e", "ef"]);
+        assert_eq!(literals(Query::new(r"\d{2}ab[cd]").mode(Mode::Regex)), vec!["ab"]);
+        assert_eq!(literals(Query::new(r"\x41bc").mode(Mode::Regex)), vec!["bc"]);
+        assert_eq!(literals(Query::new(r"\u{263A}xyz\u263Bw").mode(Mode::Regex)), vec!["xyz", "w"]);
+        assert_eq!(literals(Query::new(r"\pLab\p{Greek}cd").mode(Mode::Regex)), vec!["ab", "cd"]);
+        assert_eq!(literals(Query::new(r"\d\d-\w+x").mode(Mode::Regex)), vec!["-", "x"]);
+        assert!(literals(Query::new("(abc)?def|x").mode(Mode::Regex)).is_empty());
+    }
+
+    #[test]
+    fn invalid_patterns_are_errors() {
+        assert!(Query::new("[a").mode(Mode::Glob).matcher().is_err());
+        match Query::new("(a").mode(Mode::Regex).matcher() {
+            Err(Error::Regex(_)) => {}
+            _ => panic!("expected a regex error"),
+        }
+    }
+}